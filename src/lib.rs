@@ -1,33 +1,70 @@
 extern crate regex;
+extern crate regex_syntax;
 
 #[macro_use]
-mod rtok;
+pub mod rtok;
 
 #[cfg(test)]
 mod lib {
 
-    use rtok::tokenizer::{Tokenizer, MatcherPriority, Token};
+    use rtok::tokenizer::{Tokenizer, MatcherPriority, Token, Span, TokenizeErrorKind};
     use rtok::tokenizer::postproc::{BasicPostProcessor, PostProcessor, PostprocErr};
+    use rtok::stream::StreamError;
 
     #[test]
     fn test_whitespace() {
         let tokenizer = Tokenizer::make(MatcherPriority::First, vec![(r"(\s+)", 0)]);
-        // tokenizer.add_matcher(Matcher::new(Regex::new(r"(\s+)").unwrap(), 0));
+        // tokenizer.add_matcher(Rule::new(Regex::new(r"(\s+)").unwrap(), 0));
 
         let startstr = String::from("this is a test");
 
-        let tokens = tokenizer.tokenize(&startstr);
+        let err = tokenizer.tokenize(&startstr).unwrap_err();
 
-        assert_eq!(tokens.len(), 3);
+        // patterns only match at the current position, so "this" isn't skipped over
+        assert_eq!(err.span.start, 0);
+        assert_eq!(err.span.column, 1);
+        assert_eq!(err.text, "this");
+        assert_eq!(err.tried, vec![(0, r"(\s+)".to_string())]);
+
+        let startstr = String::from(" \t \n");
+
+        let tokens = tokenizer.tokenize(&startstr).unwrap();
+
+        assert_eq!(tokens.len(), 1);
+    }
+
+    #[test]
+    fn test_anchoring() {
+        use regex::RegexBuilder;
+        use rtok::tokenizer::Rule;
+        use rtok::bytes::{ByteTokenizer, ByteRule};
+
+        // the comment runs to the end of the pattern, so nothing may be pasted after it
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"\s+", 0), ("(?x) [a-z]+ # ident", 1), ("a|b", 2)]);
+        let types : Vec<i32> = tokenizer.tokenize("ab x").unwrap().iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![1, 0, 1]);
+        assert!(Rule::try_new("(a", 1).is_err());
+
+        // settings made on the builder are kept, and matches further on don't count
+        let mut tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"\s+", 0)]);
+        tokenizer.add_matcher(Rule::new(RegexBuilder::new("abc").case_insensitive(true).build().unwrap(), 1));
+        let types : Vec<i32> = tokenizer.tokenize("ABC abc").unwrap().iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![1, 0, 1]);
+        assert_eq!(tokenizer.tokenize(" xabc").unwrap_err().text, "xabc");
+
+        let mut tokenizer = ByteTokenizer::make(MatcherPriority::Longest, vec![(r"\s+", 0), ("(?x) [a-z]+ # ident", 1)]);
+        tokenizer.add_matcher(ByteRule::new(regex::bytes::RegexBuilder::new("abc").case_insensitive(true).build().unwrap(), 2));
+        let types : Vec<i32> = tokenizer.tokenize(b"ABC x").unwrap().iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![2, 0, 1]);
     }
 
     #[test]
     fn test_numbers() {
         let tokenizer = Tokenizer::make(MatcherPriority::First, vec![(r"^(\s+)", 0), (r"^(\d+\.\d+)",2), (r"^(\d+)", 1)]);
 
-        let startstr = String::from("1 2 3.14 5.123 123");
+        let startstr = String::from("1 2 3.25 5.123 123");
 
-        let tokens = tokenizer.tokenize(&startstr);
+        let tokens = tokenizer.tokenize(&startstr).unwrap();
 
         assert_eq!(tokens.len(),9);
     }
@@ -36,9 +73,9 @@ mod lib {
     fn test_priority_longest() {
         let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"^(\s+)", 0), (r"^(\d+)", 1), (r"^(\d+\.\d+)",2)]);
 
-        let startstr = String::from("1 234245 3.14 5.123 123");
+        let startstr = String::from("1 234245 3.25 5.123 123");
 
-        let tokens = tokenizer.tokenize(&startstr);
+        let tokens = tokenizer.tokenize(&startstr).unwrap();
 
         assert_eq!(tokens.len(), 9);
     }
@@ -47,20 +84,828 @@ mod lib {
     fn test_priority_shortest() {
         let tokenizer = Tokenizer::make(MatcherPriority::Shortest, vec![(r"^(\s+)", 0), (r"^(\d+\.\d+)",2), (r"^(\d+)", 1)]);
 
-        let startstr = String::from("1 234245 3.14 5.123 123");
+        let startstr = String::from("1 234245 3.25 5.123 123");
+
+        let err = tokenizer.tokenize(&startstr).unwrap_err();
+
+        // the shortest match splits "3.25" after the "3", and nothing matches the rest
+        assert_eq!(err.span.start, 10);
+        assert_eq!(err.text, ".25");
+    }
+
+    #[test]
+    fn test_priority_compound() {
+        use rtok::tokenizer::{Rule, TieBreak};
+        use regex::Regex;
+
+        let policy = MatcherPriority::Compound(vec![TieBreak::Longest, TieBreak::HighestPriority, TieBreak::Earliest]);
+        let mut tokenizer = Tokenizer::make(policy, vec![(r"\s+", 0), (r"[a-z]+", 1)]);
+        tokenizer.add_matcher(Rule::new(Regex::new("if|else").unwrap(), 2).with_priority(1));
 
-        let tokens = tokenizer.tokenize(&startstr);
+        let startstr = String::from("if iffy else elsewhere");
+
+        let types : Vec<i32> = tokenizer.tokenize(&startstr).unwrap().iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![2, 0, 1, 0, 2, 0, 1]);
+        assert!(tokenizer.analyze().is_clean());
+
+        let policy = MatcherPriority::Compound(vec![TieBreak::HighestPriority, TieBreak::Shortest]);
+        let tokenizer = Tokenizer::make(policy, vec![(r"a+", 0), (r"a", 1)]);
+        let types : Vec<i32> = tokenizer.tokenize("aaa").unwrap().iter().map(|t| t.typ).collect();
+        // nothing tells the two apart on the last "a", so the earlier one wins
+        assert_eq!(types, vec![1, 1, 0]);
+    }
+
+    #[test]
+    fn test_spans() {
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"^(\s+)", 0), (r"^(\d+)", 1), (r"^'(\w+)'", 2)]);
+
+        let startstr = String::from("12 'ab'\n  3");
+
+        let tokens = tokenizer.tokenize(&startstr).unwrap();
 
         assert_eq!(tokens.len(), 5);
+        assert_eq!(tokens[0].span, Span { start: 0, end: 2, line: 1, column: 1 });
+        assert_eq!(tokens[2].span, Span { start: 3, end: 7, line: 1, column: 4 });
+        assert_eq!(tokens[2].part_spans[1], Some(Span { start: 4, end: 6, line: 1, column: 5 }));
+        assert_eq!(tokens[4].span, Span { start: 10, end: 11, line: 2, column: 3 });
+        assert_eq!(&startstr[tokens[4].span.start..tokens[4].span.end], "3");
+    }
+
+    #[test]
+    fn test_empty_match() {
+        let tokenizer = Tokenizer::make(MatcherPriority::First, vec![(r"\s*", 0), (r"[a-z]+", 1)]);
+
+        assert_eq!(tokenizer.nullable_matchers(), vec![(0, r"\s*".to_string())]);
+
+        let startstr = String::from("  abc");
+
+        let results : Vec<_> = tokenizer.tokens(&startstr).collect();
+
+        assert_eq!(results.len(), 2);
+        let err = results[1].as_ref().unwrap_err();
+        assert_eq!(err.kind, TokenizeErrorKind::EmptyMatch(0));
+        assert_eq!(err.span.start, 2);
+
+        // with longest match the identifier beats the empty whitespace match
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"\s*", 0), (r"[a-z]+", 1)]);
+
+        assert_eq!(tokenizer.tokenize(&startstr).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_modes() {
+        use regex::Regex;
+        use rtok::tokenizer::Rule;
+
+        let mut tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"\s+", 0), (r"[a-z]+", 1)]);
+        tokenizer.add_matcher(Rule::new(Regex::new("\"").unwrap(), 2).push_mode("string"));
+        tokenizer.add_matcher(Rule::new(Regex::new(r"\)").unwrap(), 6).pop_mode());
+        tokenizer.add_matcher_to("string", Rule::new(Regex::new(r#"[^"\\]+"#).unwrap(), 3));
+        tokenizer.add_matcher_to("string", Rule::new(Regex::new(r"\\.").unwrap(), 4));
+        tokenizer.add_matcher_to("string", Rule::new(Regex::new("\"").unwrap(), 5).pop_mode());
+
+        let startstr = String::from(r#"say "hi \"x\" " done"#);
+
+        let types : Vec<i32> = tokenizer.tokenize(&startstr).unwrap().iter().map(|t| t.typ).collect();
+
+        assert_eq!(types, vec![1, 0, 2, 3, 4, 3, 4, 3, 5, 0, 1]);
+
+        let startstr = String::from("say )");
+
+        let err = tokenizer.tokenize(&startstr).unwrap_err();
+
+        assert_eq!(err.kind, TokenizeErrorKind::PopLastMode);
+        assert_eq!(err.span.start, 4);
+    }
+
+    #[test]
+    fn test_trivia() {
+        use std::io::BufReader;
+        use regex::Regex;
+        use rtok::tokenizer::{Rule, OwnedToken};
+
+        let mut tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"[a-z]+", 1), (r"=", 2)]);
+        tokenizer.add_matcher(Rule::new(Regex::new(r"[ \t]+").unwrap(), 0).skip());
+        tokenizer.add_matcher(Rule::new(Regex::new(r"\n").unwrap(), 3).trivia());
+        tokenizer.add_matcher(Rule::new(Regex::new(r"#[^\n]*").unwrap(), 4).trivia());
+
+        let startstr = String::from("# head\na = b # tail\n# next\nc\n");
+
+        let tokens = tokenizer.tokenize(&startstr).unwrap();
+
+        let types : Vec<i32> = tokens.iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![1, 2, 1, 1]);
+
+        let trivia = |ts: &Vec<Token>| -> Vec<i32> { ts.iter().map(|t| t.typ).collect() };
+        assert_eq!(trivia(&tokens[0].leading_trivia), vec![4, 3]);
+        assert_eq!(trivia(&tokens[0].trailing_trivia), vec![]);
+        assert_eq!(trivia(&tokens[2].trailing_trivia), vec![4, 3]);
+        assert_eq!(trivia(&tokens[3].leading_trivia), vec![4, 3]);
+        assert_eq!(trivia(&tokens[3].trailing_trivia), vec![3]);
+
+        let streamed : Vec<OwnedToken> = tokenizer.read_tokens(BufReader::with_capacity(3, startstr.as_bytes()))
+            .with_lookahead(2)
+            .map(|t| t.unwrap())
+            .collect();
+        let expected : Vec<OwnedToken> = tokens.into_iter().map(OwnedToken::from).collect();
+
+        assert_eq!(streamed, expected);
+    }
+
+    #[test]
+    fn test_builder() {
+        use regex::RegexBuilder;
+        use rtok::builder::BuildProblem;
+        use rtok::tokenizer::Rule;
+
+        let tokenizer = Tokenizer::builder(MatcherPriority::Longest)
+            .matcher_with(r"\s+", 0, |m| m.skip())
+            .matcher(r"\d+", 1)
+            .matcher("(?x) [a-z]+ # ident", 5)
+            .matcher_with("\"", 2, |m| m.push_mode("string"))
+            .in_mode("string")
+            .matcher(r#"[^"]+"#, 3)
+            .matcher_with("\"", 4, |m| m.pop_mode())
+            .build()
+            .unwrap();
+
+        let startstr = String::from(r#"1 "a b" 2 xy"#);
+
+        let types : Vec<i32> = tokenizer.tokenize(&startstr).unwrap().iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![1, 2, 3, 4, 1, 5]);
+
+        let err = Tokenizer::builder(MatcherPriority::First)
+            .matcher(r"\d+", 1)
+            .matcher(r"(\d+", 2)
+            .matcher(r"\s*", 3)
+            .matcher(r"[a-z]+", 1)
+            .matcher(r"\d+", 4)
+            .matcher(r"[A-Z]+", 4)
+            .in_mode("other")
+            .matcher_with(r"x", 1, |m| m.switch_mode("nowhere"))
+            .build()
+            .err()
+            .unwrap();
+
+        assert_eq!(err.problems.len(), 6);
+        match err.problems[0] {
+            BuildProblem::InvalidPattern { index: 1, ref pattern, .. } => assert_eq!(pattern, r"(\d+"),
+            ref p => panic!("unexpected problem {}", p),
+        }
+        match err.problems[1] {
+            BuildProblem::MatchesEmpty { index: 2, .. } => {},
+            ref p => panic!("unexpected problem {}", p),
+        }
+        match err.problems[2] {
+            BuildProblem::DuplicateType { index: 3, first: 0, to_type: 1 } => {},
+            ref p => panic!("unexpected problem {}", p),
+        }
+        match err.problems[3] {
+            BuildProblem::NeverWins { index: 4, shadowed_by: 0, .. } => {},
+            ref p => panic!("unexpected problem {}", p),
+        }
+        match err.problems[4] {
+            BuildProblem::DuplicateType { index: 5, first: 4, to_type: 4 } => {},
+            ref p => panic!("unexpected problem {}", p),
+        }
+        match err.problems[5] {
+            BuildProblem::UnknownMode { index: 6, ref mode } => assert_eq!(mode, "nowhere"),
+            ref p => panic!("unexpected problem {}", p),
+        }
+        assert!(err.to_string().starts_with("invalid tokenizer:\n  matcher 1: invalid pattern /(\\d+/"));
+
+        let err = Tokenizer::builder(MatcherPriority::Longest)
+            .matcher(r"\w{50}", 1)
+            .in_mode("big")
+            .matcher(r"\w{50}", 2)
+            .matcher(r"\d{50}", 3)
+            .matcher(r"\w{50}x", 4)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.problems.len(), 1);
+        match err.problems[0] {
+            BuildProblem::TooLarge { ref mode, .. } => assert_eq!(mode, "big"),
+            ref p => panic!("unexpected problem {}", p),
+        }
+
+        // the same text in rules of different kinds doesn't match the same things
+        let tokenizer = Tokenizer::builder(MatcherPriority::First)
+            .literal(".", 1)
+            .rule(Rule::custom("x", |input: &str| if input.starts_with("xx") { Some(2) } else { None }, 3))
+            .matcher("x", 4)
+            .rule(Rule::new(RegexBuilder::new("abc").case_insensitive(true).build().unwrap(), 5))
+            .matcher("abc", 6)
+            .matcher(".", 2)
+            .build()
+            .unwrap();
+        let types : Vec<i32> = tokenizer.tokenize(".-xxxABC").unwrap().iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_custom_matcher() {
+        use rtok::tokenizer::Rule;
+
+        fn nested_comment(input: &str) -> Option<usize> {
+            if !input.starts_with("/*") {
+                return None;
+            }
+            let (mut depth, mut i) = (0, 0);
+            while i + 2 <= input.len() {
+                match &input[i..i + 2] {
+                    "/*" => { depth += 1; i += 2; },
+                    "*/" => {
+                        depth -= 1;
+                        i += 2;
+                        if depth == 0 {
+                            return Some(i);
+                        }
+                    },
+                    _ => i += input[i..].chars().next().unwrap().len_utf8(),
+                }
+            }
+            None
+        }
+
+        let tokenizer = Tokenizer::builder(MatcherPriority::Longest)
+            .matcher_with(r"\s+", 0, |m| m.skip())
+            .matcher(r"[a-z]+", 1)
+            .matcher(r"[/*]", 2)
+            .rule(Rule::custom("nested comment", nested_comment, 3))
+            .build()
+            .unwrap();
+
+        let startstr = String::from("a /* x /* y */ z */ b */");
+
+        let tokens = tokenizer.tokenize(&startstr).unwrap();
+        let types : Vec<i32> = tokens.iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![1, 3, 1, 2, 2]);
+        assert_eq!(tokens[1].parts, vec![Some("/* x /* y */ z */")]);
+
+        let err = tokenizer.tokenize("a ?").unwrap_err();
+        assert_eq!(err.tried[3], (3, "nested comment".to_string()));
+
+        let tokenizer = Tokenizer::builder(MatcherPriority::Longest)
+            .matcher(r"[a-z]+", 1)
+            .rule(Rule::custom("past the end", |input: &str| if input.starts_with('!') { Some(input.len() + 1) } else { None }, 2))
+            .rule(Rule::custom("inside a char", |input: &str| if input.starts_with('é') { Some(1) } else { None }, 3))
+            .build()
+            .unwrap();
+        let err = tokenizer.tokenize("ab!").unwrap_err();
+        assert_eq!(err.kind, TokenizeErrorKind::BadMatchEnd(2, 2));
+        let err = tokenizer.tokenize("aé").unwrap_err();
+        assert_eq!(err.kind, TokenizeErrorKind::BadMatchEnd(3, 1));
+    }
+
+    #[test]
+    fn test_guards() {
+        use rtok::tokenizer::Rule;
+
+        let tokenizer = Tokenizer::builder(MatcherPriority::Longest)
+            .matcher_with(r"\s+", 0, |m| m.skip())
+            .matcher(r"[a-z]+", 1)
+            .literal("/", 2)
+            .matcher_with(r"/[^/\n]+/", 3, |m| m.guard(|previous| previous != Some(1) && previous != Some(3)))
+            .literal("=", 4)
+            .build()
+            .unwrap();
+
+        let types = |input: &str| -> Vec<i32> { tokenizer.tokenize(input).unwrap().iter().map(|t| t.typ).collect() };
+
+        assert_eq!(types("a / b / c"), vec![1, 2, 1, 2, 1]);
+        assert_eq!(types("a = /b c/ / d"), vec![1, 4, 3, 2, 1]);
+        assert_eq!(types("/b/"), vec![3]);
+
+        // a guard on a literal turns it off without hiding the shorter literals
+        // or another literal with the same text
+        let tokenizer = Tokenizer::builder(MatcherPriority::Longest)
+            .rule(Rule::literal(">>", 1).guard(|previous| previous != Some(9)))
+            .literal(">", 2)
+            .literal("<", 9)
+            .rule(Rule::literal("!", 5).guard(|previous| previous == Some(9)))
+            .literal("!", 6)
+            .build()
+            .unwrap();
+
+        let types = |input: &str| -> Vec<i32> { tokenizer.tokenize(input).unwrap().iter().map(|t| t.typ).collect() };
+
+        assert_eq!(types("<>>"), vec![9, 2, 2]);
+        assert_eq!(types(">>"), vec![1]);
+        assert_eq!(types("<!!"), vec![9, 5, 6]);
+    }
+
+    #[test]
+    fn test_actions() {
+        let tokenizer = Tokenizer::builder(MatcherPriority::Longest)
+            .matcher_with(r"\s+", 0, |m| m.skip())
+            .matcher(r"[a-z]+", 1)
+            .matcher_with("<", 2, |m| m.action(|token, state| {
+                state.add("angle", 1);
+                vec![token]
+            }))
+            .matcher_with(">", 3, |m| m.action(|token, state| {
+                state.add("angle", -1);
+                vec![token]
+            }))
+            .matcher_with(">>", 4, |m| m.action(|token, state| {
+                if state.get("angle") < 2 {
+                    return vec![token];
+                }
+                state.add("angle", -2);
+                vec![token.sub_token(3, 0, 1), token.sub_token(3, 1, 2)]
+            }))
+            .matcher_with("#", 5, |m| m.action(|_, _| Vec::new()))
+            .build()
+            .unwrap();
+
+        let tokens = tokenizer.tokenize("a<b<c>> # >> d").unwrap();
+        let types : Vec<i32> = tokens.iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![1, 2, 1, 2, 1, 3, 3, 4, 1]);
+        assert_eq!(tokens[6].parts[0], Some(">"));
+        assert_eq!(tokens[6].span, Span { start: 6, end: 7, line: 1, column: 7 });
+
+        let streamed : Vec<i32> = tokenizer.read_tokens("a<b<c>> # >> d".as_bytes()).map(|t| t.unwrap().typ).collect();
+        assert_eq!(streamed, types);
+    }
+
+    #[test]
+    fn test_recovering() {
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"\s+", 0), (r"[a-z]+", 1), (r"[0-9]+", 2)]);
+
+        let (tokens, errors) = tokenizer.tokenize_recovering("ab $%é 12\n!", -1);
+        let types : Vec<i32> = tokens.iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![1, 0, -1, 0, 2, 0, -1]);
+        assert_eq!(tokens[2].parts[0], Some("$%é"));
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].kind, TokenizeErrorKind::NoMatch);
+        assert_eq!(errors[0].text, "$%é");
+        assert_eq!(errors[0].span, Span { start: 3, end: 7, line: 1, column: 4 });
+        assert_eq!(errors[1].span, Span { start: 11, end: 12, line: 2, column: 1 });
+
+        let (tokens, errors) = tokenizer.tokenize_recovering("ab 12", -1);
+        assert_eq!(tokens.len(), 3);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_retokenize() {
+        use rtok::incremental::Edit;
+
+        let tokenizer = Tokenizer::builder(MatcherPriority::Longest)
+            .matcher_with(r"\s+", 0, |m| m.skip())
+            .matcher(r"[a-z]+", 1)
+            .matcher(r"[0-9]+", 2)
+            .literal("-", 3)
+            .literal("->", 4)
+            .build()
+            .unwrap();
+
+        let check = |before: &str, edit: Edit| -> std::ops::Range<usize> {
+            let mut after = before.to_string();
+            after.replace_range(edit.range.clone(), &edit.text);
+            let mut tokens = tokenizer.tokenize_owned(before).unwrap();
+            let changed = tokenizer.retokenize(&mut tokens, &edit, &after).unwrap();
+            assert_eq!(tokens, tokenizer.tokenize_owned(&after).unwrap());
+            changed
+        };
+
+        let input = "a - b 12 c\nd e\nf";
+        assert_eq!(check(input, Edit::new(3..3, ">")), 1..2);
+        assert_eq!(check(input, Edit::new(4..6, "xy\nz")), 1..4);
+        assert_eq!(check(input, Edit::new(6..7, "")), 2..4);
+        assert_eq!(check(input, Edit::new(11..14, "")), 4..5);
+        assert_eq!(check(input, Edit::new(16..16, "gh 3")), 7..9);
+        assert_eq!(check(input, Edit::new(0..0, "  ")), 0..0);
+
+        let mut tokens = tokenizer.tokenize_owned(input).unwrap();
+        assert!(tokenizer.retokenize(&mut tokens, &Edit::new(3..3, "$"), "a -$ b 12 c\nd e\nf").is_err());
+        assert_eq!(tokens, tokenizer.tokenize_owned(input).unwrap());
+
+        // closing a comment changes how the lexer reads from well before the edit
+        let tokenizer = Tokenizer::builder(MatcherPriority::Longest)
+            .matcher_with(r"\s+", 0, |m| m.skip())
+            .matcher(r"[a-z]+", 1)
+            .matcher(r"/\*[^*]*\*/", 2)
+            .literal("/", 3)
+            .literal("*", 4)
+            .build()
+            .unwrap();
+
+        let mut tokens = tokenizer.tokenize_owned("/* a b c").unwrap();
+        assert_eq!(tokenizer.retokenize(&mut tokens, &Edit::new(8..8, " */"), "/* a b c */"), Ok(0..1));
+        assert_eq!(tokens, tokenizer.tokenize_owned("/* a b c */").unwrap());
+        assert_eq!(tokens[0].typ, 2);
+    }
+
+    #[test]
+    fn test_literals() {
+        use regex::Regex;
+        use rtok::tokenizer::Rule;
+
+        let types = |tokenizer: &Tokenizer, input: &str| -> Vec<i32> {
+            tokenizer.tokenize(input).unwrap().iter().map(|t| t.typ).collect()
+        };
+
+        let mut tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"\s+", 0)]);
+        tokenizer.add_literals(vec![(":", 1), ("::", 2), ("::=", 3), ("=", 4), ("=", 6)]);
+        tokenizer.add_matcher(Rule::new(Regex::new("[a-z]+|==").unwrap(), 5));
+
+        assert_eq!(types(&tokenizer, "a ::= b :: c :="), vec![5, 0, 3, 0, 5, 0, 2, 0, 5, 0, 1, 4]);
+        assert_eq!(types(&tokenizer, "=="), vec![5]);
+
+        let analysis = tokenizer.analyze();
+        assert_eq!(analysis.findings.len(), 2);
+        assert!(analysis.findings[0].to_string().starts_with("INITIAL matcher 5: /=/ is always shadowed by matchers [4]"));
+        assert_eq!(analysis.findings[1].to_string(), "INITIAL matcher 4: ties with matcher 5 on \"=\"");
+
+        // only the longest literal competes, whatever the priority and the order
+        // the literals were added in
+        let mut tokenizer = Tokenizer::make(MatcherPriority::First, vec![(r"\s+", 0)]);
+        tokenizer.add_literals(vec![("-", 1), ("->", 2), (">", 3)]);
+        assert_eq!(types(&tokenizer, "->"), vec![2]);
+        assert_eq!(types(&tokenizer, "- >"), vec![1, 0, 3]);
+
+        let mut tokenizer = Tokenizer::make(MatcherPriority::Shortest, vec![(r"\s+", 0)]);
+        tokenizer.add_literals(vec![("->", 2), ("-", 1), (">", 3)]);
+        assert_eq!(types(&tokenizer, "->"), vec![2]);
+    }
+
+    #[test]
+    fn test_keywords() {
+        use rtok::keywords::Keywords;
+
+        let keywords = Keywords::new(1)
+            .keyword("if", 2)
+            .soft_keyword("match", 3, |previous| previous.is_none() || previous == Some(4))
+            .case_insensitive();
+
+        let tokenizer = Tokenizer::builder(MatcherPriority::Longest)
+            .matcher_with(r"\s+", 0, |m| m.skip())
+            .matcher(r"[a-zA-Z_]+", 1)
+            .matcher(r";", 4)
+            .keywords(keywords)
+            .build()
+            .unwrap();
+
+        let startstr = String::from("match IF iffy match; Match x;");
+
+        let types : Vec<i32> = tokenizer.tokenize(&startstr).unwrap().iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![3, 2, 1, 1, 4, 3, 1, 4]);
+    }
+
+    #[test]
+    fn test_analyze() {
+        use rtok::analyze::Finding;
+
+        let tokenizer = Tokenizer::make(MatcherPriority::First, vec![(r"\d+", 0), (r"\d+\.\d+", 1), (r"\s+", 2)]);
+        let analysis = tokenizer.analyze();
+
+        assert_eq!(analysis.findings.len(), 1);
+        match analysis.findings[0] {
+            Finding::Shadowed { index: 1, ref by, ref examples, .. } => {
+                assert_eq!(by, &vec![0]);
+                assert!(examples.contains(&"0.0".to_string()));
+            },
+            ref f => panic!("unexpected finding {}", f),
+        }
+
+        let tokenizer = Tokenizer::builder(MatcherPriority::Longest)
+            .matcher(r"[a-z]+", 0)
+            .matcher(r"if", 1)
+            .matcher(r"\d+", 2)
+            .in_mode("orphan")
+            .matcher(r"x", 3)
+            .build()
+            .unwrap();
+        let analysis = tokenizer.analyze();
+
+        assert_eq!(analysis.findings, vec![
+            Finding::Shadowed { mode: "INITIAL".to_string(), index: 1, pattern: "if".to_string(), by: vec![0], examples: vec!["if".to_string()] },
+            Finding::Tied { mode: "INITIAL".to_string(), index: 0, other: 1, example: "if".to_string() },
+            Finding::Unreachable { mode: "orphan".to_string(), index: 0, pattern: "x".to_string(), reason: "its mode is never entered" },
+        ]);
+
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"[a-z]+", 0), (r"\d+", 1), (r"\s+", 2)]);
+        assert!(tokenizer.analyze().is_clean());
+    }
+
+    #[test]
+    fn test_many_matchers() {
+        let words = ["if", "else", "while", "for", "return", "fn", "let", "match"];
+        let patterns : Vec<String> = words.iter().map(|w| format!("^({})", w)).collect();
+
+        let mut matchers : Vec<(&str, i32)> = vec![(r"^(\s+)", 0), (r"^([a-z]+)", 1)];
+        matchers.extend(patterns.iter().enumerate().map(|(i, p)| (p.as_str(), 10 + i as i32)));
+
+        let first = Tokenizer::make(MatcherPriority::First, matchers.clone());
+        let longest = Tokenizer::make(MatcherPriority::Longest, matchers.into_iter().rev().collect());
+
+        let startstr = String::from("while fortune return");
+
+        let types = |tokenizer: &Tokenizer| -> Vec<i32> {
+            tokenizer.tokenize(&startstr).unwrap().iter().map(|t| t.typ).collect()
+        };
+
+        assert_eq!(types(&first), vec![1, 0, 1, 0, 1]);
+        // reversed, so keywords come first and win ties against identifiers
+        assert_eq!(types(&longest), vec![12, 0, 1, 0, 14]);
+    }
+
+    #[test]
+    fn test_lazy_tokens() {
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"^(\s+)", 0), (r"^(\d+)", 1)]);
+
+        let startstr = String::from("1 2 3 x");
+
+        let first : Vec<Token> = tokenizer.tokens(&startstr)
+            .take(3)
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(first.len(), 3);
+        assert_eq!(first[2].parts[1], Some("2"));
+
+        let results : Vec<_> = tokenizer.tokens(&startstr).collect();
+
+        assert_eq!(results.len(), 7);
+        assert!(results[6].is_err());
+    }
+
+    #[test]
+    fn test_bytes() {
+        use regex::bytes::Regex;
+        use rtok::bytes::{ByteTokenizer, ByteRule};
+
+        let mut tokenizer = ByteTokenizer::make(MatcherPriority::Longest, vec![(r"[A-Z]+", 1), (r"(?-u)[\x80-\xff]+", 2), (r"(?P<num>\d+)", 3)]);
+        tokenizer.add_matcher(ByteRule::new(Regex::new(r"\s+").unwrap(), 0).skip());
+
+        let input : &[u8] = b"GET \xff\xfe 12\n 7";
+
+        let tokens = tokenizer.tokenize(input).unwrap();
+
+        let types : Vec<i32> = tokens.iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![1, 2, 3, 3]);
+        assert_eq!(tokens[1].parts[0], Some(&b"\xff\xfe"[..]));
+        assert_eq!(tokens[3].span, Span { start: 11, end: 12, line: 2, column: 2 });
+
+        let mut postproc = BasicPostProcessor::for_bytes();
+        postproc.add_postprocfn(3, |t| t.part("num").map(|n| n.iter().fold(0, |acc, d| acc * 10 + (d - b'0') as i32)));
+
+        let nums : Vec<i32> = tokens.into_iter()
+            .filter(|t| t.typ == 3)
+            .map(|t| -> Result<i32, PostprocErr> { postproc.run_on(t) })
+            .map(|n| n.unwrap())
+            .collect();
+        assert_eq!(nums, vec![12, 7]);
+
+        let err = tokenizer.tokenize(b"AB\x01\x02 C").unwrap_err();
+        assert_eq!(err.kind, TokenizeErrorKind::NoMatch);
+        assert_eq!(err.span, Span { start: 2, end: 4, line: 1, column: 3 });
+    }
+
+    #[test]
+    fn test_large_sets() {
+        use rtok::bytes::ByteTokenizer;
+
+        // each of these compiles, but together they are past the size limit of a set
+        let matchers = vec![(r"\s+", 0), (r"\w{50}", 1), (r"\d{50}", 2), (r"\w{50}x", 3)];
+        let input = format!("{}x {}", "a".repeat(50), "1".repeat(50));
+
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, matchers.clone());
+        let types : Vec<i32> = tokenizer.tokenize(&input).unwrap().iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![3, 0, 1]);
+
+        let tokenizer = ByteTokenizer::make(MatcherPriority::Longest, matchers);
+        let types : Vec<i32> = tokenizer.tokenize(input.as_bytes()).unwrap().iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![3, 0, 1]);
+    }
+
+    #[test]
+    fn test_layout() {
+        use rtok::indent::{Layout, LayoutError};
+
+        let tokenizer = Tokenizer::builder(MatcherPriority::Longest)
+            .matcher_with(r"\s+", 0, |m| m.skip())
+            .matcher_with(r"#[^\n]*", 0, |m| m.skip())
+            .allow_duplicate_types()
+            .matcher(r"[a-z]+", 1)
+            .literal(":", 2)
+            .build()
+            .unwrap();
+        let layout = Layout::new(10, 11, 12);
+
+        let startstr = String::from("a:\n  b\n  # note\n  c:\n    d\ne\n");
+
+        let tokens = tokenizer.tokenize_layout(&startstr, &layout).unwrap();
+        let types : Vec<i32> = tokens.iter().map(|t| t.typ).collect();
+        assert_eq!(types, vec![1, 2, 10, 11, 1, 10, 1, 2, 10, 11, 1, 10, 12, 12, 1, 10]);
+        assert_eq!(tokens[2].span, Span { start: 2, end: 2, line: 1, column: 3 });
+
+        let startstr = String::from("a\n    b\n  c\n");
+
+        match tokenizer.tokenize_layout(&startstr, &layout).unwrap_err() {
+            LayoutError::Dedent { span, width, levels } => {
+                assert_eq!(span, Span { start: 10, end: 11, line: 3, column: 3 });
+                assert_eq!(width, 2);
+                assert_eq!(levels, vec![0]);
+            },
+            e => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn test_line_index() {
+        use rtok::lines::{LineIndex, Position, ColumnUnit};
+
+        let input = "ab\né😀x\n\nz";
+        let index = LineIndex::new(input);
+        assert_eq!(index.line_count(), 4);
+        assert_eq!(index.line_range(2), Some((3, 10)));
+
+        assert_eq!(index.position(9, ColumnUnit::Byte), Some(Position { line: 2, column: 7 }));
+        assert_eq!(index.position(9, ColumnUnit::Char), Some(Position { line: 2, column: 3 }));
+        assert_eq!(index.position(9, ColumnUnit::Utf16), Some(Position { line: 2, column: 4 }));
+        assert_eq!(index.position(4, ColumnUnit::Char), None);
+        assert_eq!(index.position(13, ColumnUnit::Char), Some(Position { line: 4, column: 2 }));
+        assert_eq!(index.position(14, ColumnUnit::Char), None);
+
+        assert_eq!(index.offset(Position { line: 2, column: 3 }, ColumnUnit::Char), Some(9));
+        assert_eq!(index.offset(Position { line: 2, column: 4 }, ColumnUnit::Utf16), Some(9));
+        assert_eq!(index.offset(Position { line: 2, column: 3 }, ColumnUnit::Utf16), None);
+        assert_eq!(index.offset(Position { line: 2, column: 4 }, ColumnUnit::Char), Some(10));
+        assert_eq!(index.offset(Position { line: 2, column: 5 }, ColumnUnit::Char), None);
+        assert_eq!(index.offset(Position { line: 3, column: 1 }, ColumnUnit::Byte), Some(11));
+        assert_eq!(index.offset(Position { line: 5, column: 1 }, ColumnUnit::Byte), None);
+
+        // columns of token spans count chars
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"\s+", 0), (r"\S", 1)]);
+        for token in tokenizer.tokenize(input).unwrap() {
+            let position = Position { line: token.span.line, column: token.span.column };
+            assert_eq!(index.position(token.span.start, ColumnUnit::Char), Some(position));
+            assert_eq!(index.offset(position, ColumnUnit::Char), Some(token.span.start));
+        }
+    }
+
+    #[test]
+    fn test_owned_tokens() {
+        use std::thread;
+
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"\s+", 0), (r"(?P<num>\d+)", 1)]);
+
+        let startstr = String::from("12 345");
+        let tokens = tokenizer.tokenize_owned(&startstr).unwrap();
+        drop(startstr);
+
+        let handle = thread::spawn(move || tokens);
+        let tokens = handle.join().unwrap();
+
+        let mut postproc = BasicPostProcessor::new();
+        postproc.add_postprocfn(1, |t| t.part("num").map(|n| n.parse::<i32>().unwrap()));
+
+        let nums : Vec<i32> = tokens.iter()
+            .filter(|t| t.typ == 1)
+            .map(|t| -> Result<i32, PostprocErr> { postproc.run_on(t.as_token()) })
+            .map(|n| n.unwrap())
+            .collect();
+        assert_eq!(nums, vec![12, 345]);
+        assert_eq!(tokens[2].as_token().into_owned(), tokens[2]);
+    }
+
+    #[test]
+    fn test_read_tokens() {
+        use std::io::BufReader;
+        use rtok::tokenizer::OwnedToken;
+
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"^(\s+)", 0), (r"^(\d+)", 1), (r"^(\d+\.\d+)",2)]);
+
+        let input = "123 4.5\n  678 ½ 9";
+        // tiny reads, so most tokens (and the two byte '½') end up split across chunks
+        let reader = BufReader::with_capacity(2, input.as_bytes());
+
+        let tokens = tokenizer.read_tokens(reader)
+            .with_lookahead(4)
+            .collect::<Vec<_>>();
+
+        let expected : Vec<OwnedToken> = tokenizer.tokens(input)
+            .flat_map(|t| t.into_iter())
+            .map(OwnedToken::from)
+            .collect();
+
+        assert_eq!(tokens.len(), 7);
+        assert_eq!(expected.len(), 6);
+        for (token, expected) in tokens.iter().zip(expected.iter()) {
+            assert_eq!(token.as_ref().unwrap(), expected);
+        }
+        assert_eq!(tokens[2].as_ref().unwrap().parts[1], Some("4.5".to_string()));
+
+        match tokens[6] {
+            Err(StreamError::Tokenize(ref e)) => {
+                assert_eq!(e.text, "½");
+                assert_eq!(e.span.line, 2);
+                assert_eq!(e.span.column, 7);
+            },
+            _ => panic!("expected a tokenize error"),
+        }
+    }
+
+    #[test]
+    fn test_read_long_tokens() {
+        use std::cell::Cell;
+        use std::io::{self, BufReader, Read};
+        use std::rc::Rc;
+        use rtok::stream::DEFAULT_LOOKAHEAD;
+
+        let read = |tokenizer: &Tokenizer, input: &str, capacity: usize, lookahead: usize| -> Vec<i32> {
+            tokenizer.read_tokens(BufReader::with_capacity(capacity, input.as_bytes()))
+                .with_lookahead(lookahead)
+                .map(|t| t.unwrap().typ)
+                .collect()
+        };
+
+        // a string longer than the lookahead, with nothing matching up to the end
+        // of what has been read but the unterminated string
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r#""[^"]*""#, 1), (r#"""#, 2), (r"[a-z]+", 3)]);
+        let input = format!("\"{}\"", "a".repeat(5000));
+        assert_eq!(tokenizer.tokenize(&input).unwrap().len(), 1);
+        assert_eq!(read(&tokenizer, &input, 1000, DEFAULT_LOOKAHEAD), vec![1]);
+        assert_eq!(read(&tokenizer, &input[..4000], 1000, DEFAULT_LOOKAHEAD), vec![2, 3]);
+
+        let tokenizer = Tokenizer::make(MatcherPriority::First, vec![("abc", 1), ("a", 2), ("[a-z]", 3)]);
+        assert_eq!(read(&tokenizer, "abc", 1, 1), vec![1]);
+        assert_eq!(read(&tokenizer, "abd", 1, 1), vec![2, 3, 3]);
+
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"\s+", 0), (r"[a-z]+", 1)]);
+        assert_eq!(read(&tokenizer, "abc def", 1000, 0), vec![1, 0, 1]);
+
+        // input nothing can match is reported without reading on
+        struct Counting<'a>(&'a [u8], Rc<Cell<usize>>);
+        impl <'a> Read for Counting<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let read = self.0.read(buf)?;
+                self.1.set(self.1.get() + read);
+                Ok(read)
+            }
+        }
+        let input = format!("ab $ {}", "a ".repeat(1_200_000));
+        let bytes = Rc::new(Cell::new(0));
+        let reader = BufReader::with_capacity(1000, Counting(input.as_bytes(), bytes.clone()));
+        let results : Vec<_> = tokenizer.read_tokens(reader).take_while(|t| t.is_ok()).collect();
+        assert_eq!(results.len(), 2);
+        assert!(bytes.get() <= DEFAULT_LOOKAHEAD + 1000);
+    }
+
+    #[test]
+    fn test_named_parts() {
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"\s+", 0), (r"(?P<int>\d+)(?:\.(?P<frac>\d+))?", 1)]);
+
+        let startstr = String::from("12.5 7");
+
+        let tokens = tokenizer.tokenize(&startstr).unwrap();
+
+        assert_eq!(tokens[0].part("int"), Ok("12"));
+        assert_eq!(tokens[0].part("frac"), Ok("5"));
+        assert_eq!(tokens[0].part_span("frac").unwrap().start, 3);
+        assert_eq!(tokens[2].part("int"), Ok("7"));
+        assert_eq!(tokens[2].part("frac"),
+                   PostprocErr::make(1, "Capture group \"frac\" did not take part in the match".to_string()));
+        assert_eq!(tokens[2].part("fract"),
+                   PostprocErr::make(1, "Failed to find capture group \"fract\", named groups are: [int, frac]".to_string()));
+    }
+
+    #[test]
+    fn test_kinds() {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        enum Kind {
+            Space, Number, Word
+        }
+
+        let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"\s+", Kind::Space), (r"\d+", Kind::Number), (r"[a-z]+", Kind::Word)]);
+
+        let startstr = String::from("abc 12 ?");
+
+        let mut postproc = BasicPostProcessor::new();
+        postproc.add_postprocfn(Kind::Number, |t| t.part_span("0x").map(|s| s.len()));
+
+        let results : Vec<_> = tokenizer.tokens(&startstr).collect();
+
+        assert_eq!(results[0].as_ref().unwrap().typ, Kind::Word);
+        assert_eq!(results[4].as_ref().unwrap_err().tried[2], (Kind::Word, r"[a-z]+".to_string()));
+
+        let number = results.into_iter().nth(2).unwrap().unwrap();
+        let res : Result<usize, PostprocErr<Kind>> = postproc.run_on(number);
+
+        assert_eq!(res.unwrap_err().on_type(), Kind::Number);
     }
 
     #[test]
     fn test_conversion() {
         let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"^(\s+)", 0), (r"^(\d+)", 1), (r"^(\d+\.\d+)",2)]);
 
-        let startstr = String::from("1 234245 3.14 5.123 123");
+        let startstr = String::from("1 234245 3.25 5.123 123");
 
-        let tokens = tokenizer.tokenize(&startstr);
+        let tokens = tokenizer.tokenize(&startstr).unwrap();
 
         #[derive(Debug)]
         #[derive(PartialEq)]
@@ -68,7 +913,7 @@ mod lib {
             Int(i32),
             Float(f32),
             Whitespace
-        };
+        }
 
 
         let mut postproc = BasicPostProcessor::new();
@@ -89,7 +934,7 @@ mod lib {
         postproc.add_postprocfn(1, |t| {
             let tokenstr = get_token_part(&t, 1)?;
             tokenstr.parse()
-                .map(|i| TestTokenValue::Int(i))
+                .map(TestTokenValue::Int)
                 .or(PostprocErr::make(t.typ, "Failed to parse token as int".to_string()))
         });
 
@@ -97,7 +942,7 @@ mod lib {
             let tokenstr = get_token_part(&t, 1)?;
 
             tokenstr.parse()
-                .map(|i| TestTokenValue::Float(i))
+                .map(TestTokenValue::Float)
                 .or(PostprocErr::make(t.typ, "Failed to parse token as float".to_string()))
         });
 
@@ -105,10 +950,7 @@ mod lib {
 
 
         fn not_whitespace(t: &Result<TestTokenValue,PostprocErr>) -> bool {
-            match t {
-                Ok(TestTokenValue::Whitespace) => false,
-                _ => true
-            }
+            !matches!(t, Ok(TestTokenValue::Whitespace))
         }
 
         let tokenvals : Vec<Result<TestTokenValue, PostprocErr>> = tokens.into_iter()
@@ -120,8 +962,8 @@ mod lib {
 
         println!("tokenvals: {:?}", tokenvals);
 
-        assert_eq!(tokenvals.get(0), Some(&Ok(TestTokenValue::Int(1))));
-        assert_eq!(tokenvals.get(2), Some(&Ok(TestTokenValue::Float(3.14))));
+        assert_eq!(tokenvals.first(), Some(&Ok(TestTokenValue::Int(1))));
+        assert_eq!(tokenvals.get(2), Some(&Ok(TestTokenValue::Float(3.25))));
     }
 
 
@@ -144,7 +986,7 @@ mod lib {
 
             let startstr = String::from(s);
 
-            let tokens = tokenizer.tokenize(&startstr);
+            let tokens = tokenizer.tokenize(&startstr).unwrap();
 
 
             let mut postproc = BasicPostProcessor::new();
@@ -165,7 +1007,7 @@ mod lib {
             postproc.add_postprocfn(1, |t| {
                 let tokenstr = get_token_part(&t, 1)?;
                 tokenstr.parse()
-                    .map(|i| TestTokenValue::Int(i))
+                    .map(TestTokenValue::Int)
                     .or(PostprocErr::make(t.typ, "Failed to parse token as int".to_string()))
             });
 
@@ -173,25 +1015,17 @@ mod lib {
                 let tokenstr = get_token_part(&t, 1)?;
 
                 tokenstr.parse()
-                    .map(|i| TestTokenValue::Float(i))
+                    .map(TestTokenValue::Float)
                     .or(PostprocErr::make(t.typ, "Failed to parse token as float".to_string()))
             });
             postproc.add_postprocfn(3, |t| {
                 let tokenstr = get_token_part(&t, 1)?;
 
-                tokenstr.chars().nth(0)
-                    .map(|i| TestTokenValue::Op(i))
+                tokenstr.chars().next()
+                    .map(TestTokenValue::Op)
                     .ok_or(PostprocErr::new(t.typ, "Failed to get operator from token".to_string()))
             });
 
-
-            fn not_whitespace(t: &Result<TestTokenValue,PostprocErr>) -> bool {
-                match t {
-                    Ok(TestTokenValue::Whitespace) => false,
-                    _ => true
-                }
-            }
-
             let tokenvals : Vec<TestTokenValue> = tokens.into_iter()
                 .map(|i| postproc.run_on(i))
                 .flat_map(|i| { i.into_iter() })
@@ -206,13 +1040,14 @@ mod lib {
         type TestAstPtr = Box<TestAst>;
 
         #[derive(Debug)]
+        #[allow(dead_code)]
         pub enum TestAst {
             Int(i32), Float(f32), Add(TestAstPtr, TestAstPtr), Sub(TestAstPtr, TestAstPtr), Empty
         }
 
-        impl Into<TestAst> for TestTokenValue {
-            fn into(self) -> TestAst {
-                match self {
+        impl From<TestTokenValue> for TestAst {
+            fn from(t: TestTokenValue) -> TestAst {
+                match t {
                     TestTokenValue::Int(i) => TestAst::Int(i),
                     TestTokenValue::Float(i) => TestAst::Float(i),
                     _ => TestAst::Empty
@@ -246,9 +1081,9 @@ mod lib {
                             n N_::Int(_) | n N_::Float(_) | n N_::Add(..),
                             n N_::Int(_) | n N_::Float(_) | n N_::Add(..)),
                     reduction!(N_::Add(Box::new(left), Box::new(right)); 
-                               _o -> PT_(T_::Op(_)), 
-                               left -> PR_(left),
-                               right -> PR_(right)));
+                               PT_(T_::Op(_)),
+                               PR_(left),
+                               PR_(right)));
 
                 // rule for sub
                 parser.add_rule(
@@ -256,21 +1091,16 @@ mod lib {
                             n N_::Int(_) | n N_::Float(_) | n N_::Add(..),
                             n N_::Int(_) | n N_::Float(_) | n N_::Add(..)),
                     reduction!(N_::Sub(Box::new(left), Box::new(right)); 
-                               _o -> PT_(T_::Op(_)), 
-                               left -> PR_(left),
-                               right -> PR_(right)));
+                               PT_(T_::Op(_)),
+                               PR_(left),
+                               PR_(right)));
             }
 
-            while parser.step().is_ok() {
+            while let Ok(true) = parser.step() {
                 parser.debug_print_stack();
             }
 
-            match parser.output.last() {
-                Some(&TestAst::Sub(..)) => {
-                    assert!(true)
-                }
-                _ => assert!(false)
-            }
+            assert!(matches!(parser.output.last(), Some(&TestAst::Sub(..))));
         }
     }
 }
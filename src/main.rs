@@ -1,11 +1,19 @@
 extern crate regex;
-
 #[macro_use]
-mod rtok;
+extern crate tokenizer;
+
+use regex::Regex;
+
+use tokenizer::rtok::tokenizer::{Tokenizer, MatcherPriority, Rule};
+use tokenizer::rtok::tokenizer::postproc::{BasicPostProcessor, PostProcessor};
+use tokenizer::rtok::parser::{Parser};
 
-use rtok::tokenizer::{Tokenizer, MatcherPriority, Token};
-use rtok::tokenizer::postproc::{BasicPostProcessor, PostProcessor, PostprocErr};
-use rtok::parser::{Parser};
+use std::process;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenKind {
+    Whitespace, Ident, Literal, Assign, LeftPar, RightPar, Star
+}
 
 #[derive(Debug)]
 pub enum TokenType {
@@ -13,15 +21,14 @@ pub enum TokenType {
 }
 
 type AstPtr = Box<EBNFAst>;
-type AstVec = Vec<AstPtr>;
 #[derive(Debug)]
 pub enum EBNFAst {
     Ident(String), Literal(String), Definition(AstPtr, AstPtr), Single(AstPtr), Double(AstPtr,AstPtr), OptionalLast(AstPtr, AstPtr), Or(AstPtr,AstPtr), Assign, LeftPar, RightPar, Star
 }
 
-impl Into<EBNFAst> for TokenType {
-    fn into(self) -> EBNFAst {
-        match self {
+impl From<TokenType> for EBNFAst {
+    fn from(t: TokenType) -> EBNFAst {
+        match t {
             TokenType::Ident(s)   => EBNFAst::Ident(s),
             TokenType::Literal(s) => EBNFAst::Literal(s),
             TokenType::Assign     => EBNFAst::Assign,
@@ -40,51 +47,44 @@ impl Into<EBNFAst> for TokenType {
 //     }
 // }
 
-use std::io;
-
 fn main() {
     let input = String::from("expr ::= VARIABLE");
 
-    let tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"^(\s+)", 0), 
-                                                                   (r"^([a-zA-Z0-9\-_]+)", 1), 
-                                                                   (r"^'([^']+)'", 2),
-                                                                   (r"^(::=)", 3),
-                                                                   (r"^(\()", 4),
-                                                                   (r"^(\))", 5),
-                                                                   (r"^(\*)", 6)]);
+    let mut tokenizer = Tokenizer::make(MatcherPriority::Longest, vec![(r"^(?P<ident>[a-zA-Z0-9\-_]+)", TokenKind::Ident), 
+                                                                       (r"^'(?P<literal>[^']+)'", TokenKind::Literal)]);
+    tokenizer.add_literals(vec![("::=", TokenKind::Assign),
+                                ("(",   TokenKind::LeftPar),
+                                (")",   TokenKind::RightPar),
+                                ("*",   TokenKind::Star)]);
+    tokenizer.add_matcher(Rule::new(Regex::new(r"^(\s+)").unwrap(), TokenKind::Whitespace).skip());
                                     
     let mut postproc = BasicPostProcessor::new();
 
-    fn get_token_part<'a>(t: &'a Token, i: usize) -> Result<&'a str, PostprocErr> {
-        if let Some(Some(part)) = t.parts.get(i) {
-            Ok(part)
-        } else {
-            PostprocErr::make(t.typ, format!("Failed to get token part: {}", i))
-        }
-    }
-
-    postproc.add_postprocfn(1, |t| {
-        let id = get_token_part(&t, 1)?;
+    postproc.add_postprocfn(TokenKind::Ident, |t| {
+        let id = t.part("ident")?;
         Ok(TokenType::Ident(id.to_string()))
     });
 
-    postproc.add_postprocfn(2, |t| {
-        let id = get_token_part(&t, 1)?;
+    postproc.add_postprocfn(TokenKind::Literal, |t| {
+        let id = t.part("literal")?;
         Ok(TokenType::Literal(id.to_string()))
     });
 
-    postproc.add_postprocfn(3, |_| {Ok(TokenType::Assign)});
-    postproc.add_postprocfn(4, |_| {Ok(TokenType::LeftPar)});
-    postproc.add_postprocfn(5, |_| {Ok(TokenType::RightPar)});
-    postproc.add_postprocfn(6, |_| {Ok(TokenType::Star)});
- 
-    fn not_whitespace(t: &Token) -> bool {
-        t.typ != 0
-    }
+    postproc.add_postprocfn(TokenKind::Assign,   |_| {Ok(TokenType::Assign)});
+    postproc.add_postprocfn(TokenKind::LeftPar,  |_| {Ok(TokenType::LeftPar)});
+    postproc.add_postprocfn(TokenKind::RightPar, |_| {Ok(TokenType::RightPar)});
+    postproc.add_postprocfn(TokenKind::Star,     |_| {Ok(TokenType::Star)});
+
+    let tokens = match tokenizer.tokenize(&input) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            eprintln!("tokenize error: {}", e);
+            process::exit(1);
+        }
+    };
 
-    let tokens : Vec<TokenType> = tokenizer.tokenize(&input)
+    let tokens : Vec<TokenType> = tokens
         .into_iter()
-        .filter(not_whitespace)
         .map(|i| postproc.run_on(i))
         .flat_map(|i| i.into_iter())
         .rev()
@@ -93,11 +93,10 @@ fn main() {
     let mut parser : Parser<TokenType, EBNFAst> = Parser::new(tokens);
 
     {
-        use rtok::parser::{ParseError, ParseValue};
+        use tokenizer::rtok::parser::{ParseError, ParseValue};
         use self::TokenType as T;
         use self::EBNFAst as N;
-        use rtok::parser::ParseValue::Token as PT;
-        use rtok::parser::ParseValue::Reduced as PR;
+        use tokenizer::rtok::parser::ParseValue::Reduced as PR;
 
         wrap_intos!(parser; T::Ident(_), T::Literal(_), T::Assign, T::LeftPar, T::RightPar, T::Star);
 
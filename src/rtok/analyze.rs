@@ -0,0 +1,261 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use regex_syntax::Parser;
+use regex_syntax::hir::{Hir, HirKind, Literal, Class, RepetitionKind, RepetitionRange};
+
+use rtok::tokenizer::{Tokenizer, ModeChange};
+
+// Upper bound on the examples kept for any part of a pattern, so nested
+// repetitions and alternations can't blow up.
+const MAX_EXAMPLES: usize = 32;
+const MAX_SHOWN: usize = 3;
+
+// Something suspicious about the `index`th matcher of `mode`. Findings are based
+// on example strings generated from the patterns, so a matcher that isn't
+// reported may still be shadowed on inputs that weren't tried. Guards are taken
+// to let every matcher through.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Finding {
+    // the matcher can never produce a token
+    Unreachable { mode: String, index: usize, pattern: String, reason: &'static str },
+    // on every example it matches, one of the matchers in `by` wins instead
+    Shadowed { mode: String, index: usize, pattern: String, by: Vec<usize>, examples: Vec<String> },
+    // the tie breaks can't tell the two matches of `example` apart, so only the
+    // order of the matchers decides; not reported when that is the policy anyway
+    Tied { mode: String, index: usize, other: usize, example: String },
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Finding::Unreachable { mode, index, pattern, reason } =>
+                write!(f, "{} matcher {}: /{}/ is unreachable, {}", mode, index, pattern, reason),
+            Finding::Shadowed { mode, index, pattern, by, examples } =>
+                write!(f, "{} matcher {}: /{}/ is always shadowed by matchers {:?}, e.g. on {:?}", mode, index, pattern, by, examples),
+            Finding::Tied { mode, index, other, example } =>
+                write!(f, "{} matcher {}: ties with matcher {} on {:?}", mode, index, other, example),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    pub findings: Vec<Finding>,
+}
+
+impl Analysis {
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} finding(s)", self.findings.len())?;
+        for finding in &self.findings {
+            write!(f, "\n  {}", finding)?;
+        }
+        Ok(())
+    }
+}
+
+impl <K: Copy + PartialEq> Tokenizer<K> {
+    // Looks for matchers that can't fire or that overlap with others, by running
+    // the tokenizer's own priority rules on examples built from each pattern.
+    pub fn analyze(&self) -> Analysis {
+        let reachable = self.reachable_modes();
+        let mut findings = Vec::new();
+
+        for (m, mode) in self.modes.iter().enumerate() {
+            // for each matcher, the non-empty prefixes of its examples it matches;
+            // custom matchers have nothing to make examples from
+            let own : Vec<Vec<String>> = mode.matchers.iter()
+                .map(|matcher| {
+                    let examples = match matcher.literal_text() {
+                        Some(text) => vec![text.to_string()],
+                        None if matcher.is_custom() => Vec::new(),
+                        None => examples(&matcher.source),
+                    };
+                    let mut own : Vec<String> = examples.into_iter()
+                        .filter_map(|e| matcher.find(&e).unwrap_or(None).map(|end| e[..end].to_string()))
+                        .filter(|e| !e.is_empty())
+                        .collect();
+                    own.sort();
+                    own.dedup();
+                    own
+                })
+                .collect();
+
+            for (index, matcher) in mode.matchers.iter().enumerate() {
+                let unreachable = |reason| Finding::Unreachable {
+                    mode: mode.name.clone(), index, pattern: matcher.source.clone(), reason,
+                };
+                if !reachable[m] {
+                    findings.push(unreachable("its mode is never entered"));
+                    continue;
+                }
+                if own[index].is_empty() {
+                    if matcher.matches_empty() && !matcher.is_custom() {
+                        findings.push(unreachable("it only matches the empty string"));
+                    }
+                    continue;
+                }
+
+                let mut by = Vec::new();
+                let mut shown = Vec::new();
+                let mut wins = false;
+                for example in &own[index] {
+                    let winner = match self.find_match(mode, example, |_| true) {
+                        Ok((Some((winner, _)), _)) => winner,
+                        _ => continue,
+                    };
+                    if winner == index {
+                        wins = true;
+                        break;
+                    }
+                    if !by.contains(&winner) {
+                        by.push(winner);
+                    }
+                    if shown.len() < MAX_SHOWN {
+                        shown.push(example.clone());
+                    }
+                }
+                if !wins && !by.is_empty() {
+                    by.sort();
+                    findings.push(Finding::Shadowed {
+                        mode: mode.name.clone(), index, pattern: matcher.source.clone(), by, examples: shown,
+                    });
+                }
+            }
+
+            if self.priority.first_wins() || !reachable[m] {
+                continue;
+            }
+
+            let mut tied : Vec<(usize, usize)> = Vec::new();
+            for example in own.iter().flat_map(|e| e.iter()) {
+                let winner = match self.find_match(mode, example, |_| true) {
+                    Ok((Some((winner, _)), _)) => winner,
+                    _ => continue,
+                };
+                let rank = |idx: usize| {
+                    let matcher = &mode.matchers[idx];
+                    matcher.find(example).unwrap_or(None).map(|end| (end, matcher.priority))
+                };
+                let best = rank(winner).unwrap();
+                for other in winner + 1..mode.matchers.len() {
+                    let tie = rank(other).is_some_and(|r| self.priority.compare(r, best) == Ordering::Equal);
+                    if tie && !tied.contains(&(winner, other)) {
+                        tied.push((winner, other));
+                        findings.push(Finding::Tied {
+                            mode: mode.name.clone(), index: winner, other, example: example.clone(),
+                        });
+                    }
+                }
+            }
+        }
+
+        Analysis { findings }
+    }
+
+    // Which modes can be on top of the mode stack, starting from the initial one.
+    fn reachable_modes(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.modes.len()];
+        let mut todo = vec![0];
+        while let Some(m) = todo.pop() {
+            if reachable[m] {
+                continue;
+            }
+            reachable[m] = true;
+            for matcher in &self.modes[m].matchers {
+                match matcher.mode_change {
+                    ModeChange::Push(ref name) | ModeChange::Switch(ref name) => {
+                        todo.extend(self.mode_index(name));
+                    },
+                    ModeChange::Stay | ModeChange::Pop => {},
+                }
+            }
+        }
+        reachable
+    }
+}
+
+// Strings the pattern should match, preferring printable ASCII. Anchors and word
+// boundaries are ignored here, so some examples may not actually match.
+fn examples(pattern: &str) -> Vec<String> {
+    match Parser::new().parse(pattern) {
+        Ok(hir) => examples_of(&hir),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn examples_of(hir: &Hir) -> Vec<String> {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => vec![String::new()],
+        HirKind::Literal(Literal::Unicode(c)) => vec![c.to_string()],
+        HirKind::Literal(Literal::Byte(b)) if *b < 0x80 => vec![(*b as char).to_string()],
+        HirKind::Literal(Literal::Byte(_)) => Vec::new(),
+        HirKind::Class(Class::Unicode(class)) => pick_chars(class.iter().map(|r| (r.start(), r.end()))),
+        HirKind::Class(Class::Bytes(class)) => pick_chars(class.iter()
+            .filter(|r| r.start() < 0x80)
+            .map(|r| (r.start() as char, r.end().min(0x7f) as char))),
+        HirKind::Repetition(rep) => {
+            let (min, max) = match rep.kind {
+                RepetitionKind::ZeroOrOne => (0, 1),
+                RepetitionKind::ZeroOrMore => (0, 2),
+                RepetitionKind::OneOrMore => (1, 2),
+                RepetitionKind::Range(RepetitionRange::Exactly(n)) => (n, n),
+                RepetitionKind::Range(RepetitionRange::AtLeast(n)) => (n, n + 1),
+                RepetitionKind::Range(RepetitionRange::Bounded(m, n)) => (m, n.min(m + 1)),
+            };
+            let once = examples_of(&rep.hir);
+            let mut all = Vec::new();
+            for count in min..=max {
+                let repeated = (0..count).fold(vec![String::new()], |acc, _| concat(&acc, &once));
+                all.extend(repeated);
+            }
+            all.truncate(MAX_EXAMPLES);
+            all
+        },
+        HirKind::Group(group) => examples_of(&group.hir),
+        HirKind::Concat(hirs) => hirs.iter().fold(vec![String::new()], |acc, hir| concat(&acc, &examples_of(hir))),
+        HirKind::Alternation(hirs) => {
+            // take from each branch in turn, so a big first branch can't crowd out the rest
+            let branches : Vec<Vec<String>> = hirs.iter().map(examples_of).collect();
+            let longest = branches.iter().map(|b| b.len()).max().unwrap_or(0);
+            (0..longest)
+                .flat_map(|i| branches.iter().filter_map(move |b| b.get(i).cloned()))
+                .take(MAX_EXAMPLES)
+                .collect()
+        },
+    }
+}
+
+fn concat(prefixes: &[String], suffixes: &[String]) -> Vec<String> {
+    prefixes.iter()
+        .flat_map(|p| suffixes.iter().map(move |s| format!("{}{}", p, s)))
+        .take(MAX_EXAMPLES)
+        .collect()
+}
+
+// A few chars out of a class: the ends of its printable ASCII ranges if it has
+// any, otherwise its very first char.
+fn pick_chars<I: Iterator<Item = (char, char)>>(ranges: I) -> Vec<String> {
+    let ranges : Vec<(char, char)> = ranges.collect();
+    let mut picked : Vec<char> = Vec::new();
+    for &(start, end) in &ranges {
+        if end < ' ' || start > '~' {
+            continue;
+        }
+        for c in &[start.max(' '), end.min('~')] {
+            if !picked.contains(c) {
+                picked.push(*c);
+            }
+        }
+    }
+    if picked.is_empty() {
+        picked.extend(ranges.first().map(|r| r.0));
+    }
+    picked.into_iter().take(MAX_SHOWN).map(|c| c.to_string()).collect()
+}
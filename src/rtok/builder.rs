@@ -0,0 +1,224 @@
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+
+use regex;
+
+use rtok::keywords::Keywords;
+use rtok::tokenizer::{Tokenizer, Rule, MatcherPriority, ModeChange, TokenTypeId, INITIAL_MODE};
+
+// Something wrong with the matcher added as the `index`th one (counting from 0
+// across all modes), or with a whole mode.
+#[derive(Debug)]
+pub enum BuildProblem<K = TokenTypeId> {
+    InvalidPattern { index: usize, pattern: String, error: regex::Error },
+    // the pattern matches the empty string, which stops lexing wherever it wins
+    MatchesEmpty { index: usize, pattern: String },
+    // another matcher in the same mode already produces this type
+    DuplicateType { index: usize, first: usize, to_type: K },
+    // an earlier matcher in the same mode has the same pattern of the same kind
+    // (see `Rule::same_pattern`), no guard and a priority that is preferred at
+    // least as much
+    NeverWins { index: usize, pattern: String, shadowed_by: usize },
+    UnknownMode { index: usize, mode: String },
+    // the regex matchers of the mode are too much to compile together
+    TooLarge { mode: String, error: regex::Error },
+}
+
+impl <K: fmt::Debug> fmt::Display for BuildProblem<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildProblem::InvalidPattern { index, pattern, error } =>
+                write!(f, "matcher {}: invalid pattern /{}/: {}", index, pattern, error),
+            BuildProblem::MatchesEmpty { index, pattern } =>
+                write!(f, "matcher {}: pattern /{}/ matches the empty string", index, pattern),
+            BuildProblem::DuplicateType { index, first, to_type } =>
+                write!(f, "matcher {}: type {:?} is already produced by matcher {}", index, to_type, first),
+            BuildProblem::NeverWins { index, pattern, shadowed_by } =>
+                write!(f, "matcher {}: pattern /{}/ can never win over matcher {}", index, pattern, shadowed_by),
+            BuildProblem::UnknownMode { index, mode } =>
+                write!(f, "matcher {}: mode {:?} has no matchers", index, mode),
+            BuildProblem::TooLarge { mode, error } =>
+                write!(f, "mode {:?}: matchers can't be compiled together: {}", mode, error),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BuildError<K = TokenTypeId> {
+    pub problems: Vec<BuildProblem<K>>,
+}
+
+impl <K: fmt::Debug> fmt::Display for BuildError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid tokenizer:")?;
+        for problem in &self.problems {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl <K: fmt::Debug> Error for BuildError<K> {}
+
+type Configure<K> = Box<dyn FnOnce(Rule<K>) -> Rule<K>>;
+
+enum Spec<K> {
+    Pattern { pattern: String, to_type: K, configure: Configure<K> },
+    Rule(Rule<K>),
+}
+
+struct Entry<K> {
+    mode: String,
+    spec: Spec<K>,
+}
+
+// Collects patterns as strings and checks all of them at once in `build`, instead
+// of panicking on the first bad one like `Tokenizer::make`.
+pub struct TokenizerBuilder<K = TokenTypeId> {
+    priority: MatcherPriority,
+    mode: String,
+    entries: Vec<Entry<K>>,
+    keywords: Vec<(String, Keywords<K>)>,
+    allow_duplicate_types: bool,
+}
+
+impl <K: Copy + PartialEq> Tokenizer<K> {
+    pub fn builder(priority: MatcherPriority) -> TokenizerBuilder<K> {
+        TokenizerBuilder {
+            priority,
+            mode: INITIAL_MODE.to_string(),
+            entries: Vec::new(),
+            keywords: Vec::new(),
+            allow_duplicate_types: false,
+        }
+    }
+}
+
+impl <K: Copy + PartialEq> TokenizerBuilder<K> {
+    // Matchers added after this go into `mode`.
+    pub fn in_mode(mut self, mode: &str) -> TokenizerBuilder<K> {
+        self.mode = mode.to_string();
+        self
+    }
+
+    pub fn matcher(self, pattern: &str, to_type: K) -> TokenizerBuilder<K> {
+        self.matcher_with(pattern, to_type, |m| m)
+    }
+
+    // Like `matcher`, with `configure` applied to the matcher once its pattern is
+    // compiled, e.g. `|m| m.skip()`.
+    pub fn matcher_with<F>(mut self, pattern: &str, to_type: K, configure: F) -> TokenizerBuilder<K>
+    where F: 'static + FnOnce(Rule<K>) -> Rule<K> {
+        let spec = Spec::Pattern { pattern: pattern.to_string(), to_type, configure: Box::new(configure) };
+        self.entries.push(Entry { mode: self.mode.clone(), spec });
+        self
+    }
+
+    pub fn literal(self, text: &str, to_type: K) -> TokenizerBuilder<K> {
+        self.rule(Rule::literal(text, to_type))
+    }
+
+    // Adds a ready-made rule, e.g. one for a custom matcher.
+    pub fn rule(mut self, rule: Rule<K>) -> TokenizerBuilder<K> {
+        self.entries.push(Entry { mode: self.mode.clone(), spec: Spec::Rule(rule) });
+        self
+    }
+
+    // Keywords for the tokens of the current mode, see `Tokenizer::add_keywords_to`.
+    pub fn keywords(mut self, keywords: Keywords<K>) -> TokenizerBuilder<K> {
+        self.keywords.push((self.mode.clone(), keywords));
+        self
+    }
+
+    // Several matchers of a mode may produce the same type.
+    pub fn allow_duplicate_types(mut self) -> TokenizerBuilder<K> {
+        self.allow_duplicate_types = true;
+        self
+    }
+
+    pub fn build(self) -> Result<Tokenizer<K>, BuildError<K>> {
+        let mut problems = Vec::new();
+        let mut matchers = Vec::new();
+
+        let mut known_modes = vec![INITIAL_MODE.to_string()];
+        known_modes.extend(self.entries.iter().map(|e| e.mode.clone()));
+
+        for (index, entry) in self.entries.into_iter().enumerate() {
+            let matcher = match entry.spec {
+                Spec::Pattern { pattern, to_type, configure } => match Rule::try_new(&pattern, to_type) {
+                    Ok(rule) => configure(rule),
+                    Err(error) => {
+                        problems.push(BuildProblem::InvalidPattern { index, pattern, error });
+                        continue;
+                    },
+                },
+                Spec::Rule(rule) => rule,
+            };
+            if matcher.matches_empty() {
+                problems.push(BuildProblem::MatchesEmpty { index, pattern: matcher.source.clone() });
+            }
+            matchers.push((index, entry.mode, matcher));
+        }
+
+        for (i, &(index, ref mode, ref matcher)) in matchers.iter().enumerate() {
+            let earlier = matchers[..i].iter().filter(|&(_, m, _)| m == mode);
+
+            for &(first, _, ref other) in earlier {
+                let preferred = self.priority.compare((0, other.priority), (0, matcher.priority)) != Ordering::Less;
+                if other.same_pattern(matcher) && other.guard.is_none() && preferred {
+                    problems.push(BuildProblem::NeverWins { index, pattern: matcher.source.clone(), shadowed_by: first });
+                    break;
+                }
+                if !self.allow_duplicate_types && other.to_type == matcher.to_type {
+                    problems.push(BuildProblem::DuplicateType { index, first, to_type: matcher.to_type });
+                    break;
+                }
+            }
+
+            match matcher.mode_change {
+                ModeChange::Push(ref target) | ModeChange::Switch(ref target) => {
+                    if !known_modes.contains(target) {
+                        problems.push(BuildProblem::UnknownMode { index, mode: target.clone() });
+                    }
+                },
+                ModeChange::Stay | ModeChange::Pop => {},
+            }
+        }
+
+        let mut tokenizer = Tokenizer::new(self.priority);
+        let mut modes : Vec<(String, Vec<Rule<K>>)> = Vec::new();
+        for (_, mode, matcher) in matchers {
+            match modes.iter().position(|m| m.0 == mode) {
+                Some(idx) => modes[idx].1.push(matcher),
+                None => modes.push((mode, vec![matcher])),
+            }
+        }
+        for (mode, matchers) in modes {
+            tokenizer.add_matchers_to(&mode, matchers);
+        }
+        // mode problems go after the matcher ones
+        for mode in &tokenizer.modes {
+            if let Some(error) = mode.set_error() {
+                problems.push(BuildProblem::TooLarge { mode: mode.name.clone(), error: error.clone() });
+            }
+        }
+
+        if !problems.is_empty() {
+            problems.sort_by_key(|p| match *p {
+                BuildProblem::InvalidPattern { index, .. } |
+                BuildProblem::MatchesEmpty { index, .. } |
+                BuildProblem::DuplicateType { index, .. } |
+                BuildProblem::NeverWins { index, .. } |
+                BuildProblem::UnknownMode { index, .. } => index,
+                BuildProblem::TooLarge { .. } => usize::MAX,
+            });
+            return Err(BuildError { problems });
+        }
+
+        for (mode, keywords) in self.keywords {
+            tokenizer.add_keywords_to(&mode, keywords);
+        }
+        Ok(tokenizer)
+    }
+}
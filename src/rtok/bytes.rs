@@ -0,0 +1,226 @@
+use regex::bytes::{Regex, RegexSet};
+
+use rtok::tokenizer::{Token, TokenTypeId, Span, CaptureNames, MatcherPriority, TokenizeError, TokenizeErrorKind, anchored, capture_names};
+
+pub type ByteToken<'a, K = TokenTypeId> = Token<'a, K, [u8]>;
+
+// A matcher of a `ByteTokenizer`. Like `Rule`, the pattern only matches at the
+// current position; use `(?-u)` to match bytes that aren't valid UTF-8.
+pub struct ByteRule<K = TokenTypeId> {
+    source: String,
+    pattern: Regex,
+    // whether `pattern` was anchored when the rule was made, see `Rule::new`
+    anchored: bool,
+    names: CaptureNames,
+    to_type: K,
+    skip: bool,
+    priority: i32,
+}
+
+impl <K> ByteRule<K> {
+    pub fn new(pattern: Regex, to_type: K) -> ByteRule<K> {
+        ByteRule::with_pattern(pattern.as_str().to_string(), pattern, false, to_type)
+    }
+
+    pub fn try_new(pattern: &str, to_type: K) -> Result<ByteRule<K>, regex::Error> {
+        let anchored = Regex::new(&anchored(pattern, true)?)?;
+        Ok(ByteRule::with_pattern(pattern.to_string(), anchored, true, to_type))
+    }
+
+    fn with_pattern(source: String, pattern: Regex, anchored: bool, to_type: K) -> ByteRule<K> {
+        let names = capture_names(pattern.capture_names());
+        ByteRule { source, pattern, anchored, names, to_type, skip: false, priority: 0 }
+    }
+
+    pub fn skip(mut self) -> ByteRule<K> {
+        self.skip = true;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> ByteRule<K> {
+        self.priority = priority;
+        self
+    }
+
+    pub fn matches_empty(&self) -> bool {
+        self.find(b"") == Some(0)
+    }
+
+    fn find(&self, input: &[u8]) -> Option<usize> {
+        self.pattern.find(input).filter(|m| m.start() == 0).map(|m| m.end())
+    }
+}
+
+// Lexes bytes instead of text. Picks matches by the same `MatcherPriority` rules
+// as `Tokenizer`, but has no modes, trivia or keywords. Columns in spans count
+// bytes, not chars.
+pub struct ByteTokenizer<K = TokenTypeId> {
+    matchers: Vec<ByteRule<K>>,
+    // the anchored matchers, and their indices into `matchers`
+    set: RegexSet,
+    in_set: Vec<usize>,
+    // the indices of the rest
+    others: Vec<usize>,
+    priority: MatcherPriority,
+}
+
+impl <K: Copy> ByteTokenizer<K> {
+    pub fn new(priority: MatcherPriority) -> ByteTokenizer<K> {
+        ByteTokenizer {
+            matchers: Vec::new(),
+            set: RegexSet::new(Vec::<&str>::new()).unwrap(),
+            in_set: Vec::new(),
+            others: Vec::new(),
+            priority,
+        }
+    }
+
+    // Panics if a pattern isn't a valid regex.
+    pub fn make(priority: MatcherPriority, matchers: Vec<(&str, K)>) -> ByteTokenizer<K> {
+        let mut tokenizer = ByteTokenizer::new(priority);
+        for (pattern, to_type) in matchers {
+            tokenizer.matchers.push(ByteRule::try_new(pattern, to_type).unwrap());
+        }
+        tokenizer.compile_set();
+        tokenizer
+    }
+
+    pub fn add_matcher(&mut self, matcher: ByteRule<K>) {
+        self.matchers.push(matcher);
+        self.compile_set();
+    }
+
+    fn compile_set(&mut self) {
+        let (in_set, others) = (0..self.matchers.len()).partition(|&idx| self.matchers[idx].anchored);
+        self.in_set = in_set;
+        self.others = others;
+        // each pattern compiled on its own, but together they may still be too big,
+        // in which case they are tried one by one
+        match RegexSet::new(self.in_set.iter().map(|&idx| self.matchers[idx].pattern.as_str())) {
+            Ok(set) => self.set = set,
+            Err(_) => {
+                self.others.append(&mut self.in_set);
+                self.others.sort();
+                self.set = RegexSet::new(Vec::<&str>::new()).unwrap();
+            },
+        }
+    }
+
+    // Indices of the matchers that may match at the start of `current`, in order.
+    fn candidates(&self, current: &[u8]) -> Vec<usize> {
+        let mut candidates : Vec<usize> = self.set.matches(current).iter().map(|i| self.in_set[i]).collect();
+        if !self.others.is_empty() {
+            candidates.extend(&self.others);
+            candidates.sort();
+        }
+        candidates
+    }
+
+    pub fn tokenize<'a>(&self, input: &'a [u8]) -> Result<Vec<ByteToken<'a, K>>, TokenizeError<K>> {
+        self.tokens(input).collect()
+    }
+
+    pub fn tokens<'t, 'a>(&'t self, input: &'a [u8]) -> ByteTokens<'t, 'a, K> {
+        ByteTokens { tokenizer: self, input, offset: 0, line: 1, column: 1, done: false }
+    }
+
+    fn error(&self, kind: TokenizeErrorKind<K>, span: Span, rest: &[u8]) -> TokenizeError<K> {
+        let len = rest.iter().skip(1).position(|b| b.is_ascii_whitespace()).map_or(rest.len(), |i| i + 1).min(32);
+        TokenizeError {
+            kind,
+            span: Span { end: span.start + len, ..span },
+            text: String::from_utf8_lossy(&rest[..len]).into_owned(),
+            tried: self.matchers.iter().map(|m| (m.to_type, m.source.clone())).collect(),
+        }
+    }
+}
+
+pub struct ByteTokens<'t, 'a, K: 't = TokenTypeId> {
+    tokenizer: &'t ByteTokenizer<K>,
+    input: &'a [u8],
+    offset: usize,
+    line: usize,
+    column: usize,
+    done: bool,
+}
+
+impl <'t, 'a, K: Copy> ByteTokens<'t, 'a, K> {
+    fn span(&self, start: usize, end: usize) -> Span {
+        let before = &self.input[self.offset..start];
+        match before.iter().rposition(|&b| b == b'\n') {
+            Some(nl) => Span {
+                start, end,
+                line: self.line + before.iter().filter(|&&b| b == b'\n').count(),
+                column: before.len() - nl,
+            },
+            None => Span { start, end, line: self.line, column: self.column + before.len() },
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Option<ByteToken<'a, K>>, TokenizeError<K>> {
+        let tokenizer = self.tokenizer;
+        loop {
+            let current = &self.input[self.offset..];
+            if current.is_empty() {
+                return Ok(None);
+            }
+
+            let matches = tokenizer.candidates(current).into_iter()
+                .filter_map(|idx| {
+                    let matcher = &tokenizer.matchers[idx];
+                    matcher.find(current).map(|end| (idx, end, matcher.priority))
+                });
+            let here = self.span(self.offset, self.offset);
+            let (idx, end) = match tokenizer.priority.pick(matches).0 {
+                Some(found) => found,
+                None => return Err(tokenizer.error(TokenizeErrorKind::NoMatch, here, current)),
+            };
+            let matcher = &tokenizer.matchers[idx];
+            if end == 0 {
+                return Err(tokenizer.error(TokenizeErrorKind::EmptyMatch(matcher.to_type), here, current));
+            }
+
+            let captures = matcher.pattern.captures(current).unwrap();
+            let base = self.offset;
+            let token = Token {
+                typ: matcher.to_type,
+                parts: captures.iter().map(|g| g.map(|g| g.as_bytes())).collect(),
+                span: self.span(base, base + end),
+                part_spans: captures.iter().map(|g| g.map(|g| self.span(base + g.start(), base + g.end()))).collect(),
+                names: matcher.names.clone(),
+                leading_trivia: Vec::new(),
+                trailing_trivia: Vec::new(),
+            };
+
+            let after = self.span(base + end, base + end);
+            self.offset = base + end;
+            self.line = after.line;
+            self.column = after.column;
+
+            if !matcher.skip {
+                return Ok(Some(token));
+            }
+        }
+    }
+}
+
+impl <'t, 'a, K: Copy> Iterator for ByteTokens<'t, 'a, K> {
+    type Item = Result<ByteToken<'a, K>, TokenizeError<K>>;
+
+    fn next(&mut self) -> Option<Result<ByteToken<'a, K>, TokenizeError<K>>> {
+        if self.done {
+            return None;
+        }
+        match self.next_token() {
+            Ok(Some(token)) => Some(Ok(token)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::ops::Range;
+
+use rtok::keywords::Keywords;
+use rtok::tokenizer::{Tokenizer, OwnedToken, Span, TokenizeError, Cursor, Step, Channel};
+
+// A change to the input: the bytes `range` of the old input were replaced with
+// `text`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+impl Edit {
+    pub fn new(range: Range<usize>, text: &str) -> Edit {
+        Edit { range, text: text.to_string() }
+    }
+
+    // How much later everything after the edit ends up.
+    fn delta(&self) -> isize {
+        self.text.len() as isize - self.range.len() as isize
+    }
+}
+
+impl <K: Copy + PartialEq> Tokenizer<K> {
+    // Brings `tokens`, lexed from the input before `edit`, up to date with `input`,
+    // the input after it. Lexing restarts at the last token starting no later than
+    // where some matcher could have matched on into the edit, since the lexer may
+    // have looked that far (`-` then `>`, or an unterminated comment), and stops as
+    // soon as a token lines up with an old one past the edit.
+    // The old tokens from there on are kept, only moved. Returns the range of
+    // `tokens` that was lexed again; on error `tokens` is left as it was.
+    //
+    // When lexing depends on more than the text at hand — modes, rule actions,
+    // guards, soft keywords or trivia — the whole input is lexed again.
+    pub fn retokenize(&self, tokens: &mut Vec<OwnedToken<K>>, edit: &Edit, input: &str) -> Result<Range<usize>, TokenizeError<K>> {
+        if self.is_stateful() {
+            *tokens = self.tokenize_owned(input)?;
+            return Ok(0..tokens.len());
+        }
+
+        let delta = edit.delta();
+        let edit_end = edit.range.start + edit.text.len();
+        let touched = tokens.partition_point(|t| t.span.end < edit.range.start);
+        let reach = self.modes[0].reach(&input[..edit.range.start]);
+        let first = tokens.partition_point(|t| t.span.start <= reach).saturating_sub(1).min(touched);
+        let (mut cursor, mut pos) = match tokens.get(first) {
+            Some(token) if token.span.start <= reach => (Cursor::at(token.span), token.span.start),
+            _ => (Cursor::start(), 0),
+        };
+
+        // the first old token past the edit, and the new token it lines up with
+        let mut old = tokens.partition_point(|t| t.span.start < edit.range.end);
+        let mut synced = None;
+        let mut relexed = Vec::new();
+        while pos < input.len() {
+            let (token, consumed, channel) = match self.step(&mut cursor, &input[pos..], true) {
+                Step::Token(token, consumed, channel) => (token, consumed, channel),
+                Step::Failed(e) => return Err(e),
+                Step::Tokens(..) | Step::NeedMore => unreachable!("stateless lexing of complete input"),
+            };
+            pos += consumed;
+            if channel != Channel::Main {
+                continue;
+            }
+
+            if token.span.start >= edit_end {
+                let moved = |t: &OwnedToken<K>| (t.span.start as isize + delta) as usize;
+                while old < tokens.len() && moved(&tokens[old]) < token.span.start {
+                    old += 1;
+                }
+                let same = tokens.get(old).is_some_and(|t| {
+                    moved(t) == token.span.start && t.span.len() == token.span.len() && t.typ == token.typ
+                });
+                if same {
+                    synced = Some(token.span);
+                    break;
+                }
+            }
+            relexed.push(token.into_owned());
+        }
+
+        let end = match synced {
+            Some(_) => old,
+            None => tokens.len(),
+        };
+        let changed = first..first + relexed.len();
+        tokens.splice(first..end, relexed);
+
+        if let Some(new) = synced {
+            let old_span = tokens[changed.end].span;
+            let shift = |span: &mut Span| {
+                if span.line == old_span.line {
+                    span.column = span.column + new.column - old_span.column;
+                }
+                span.line = span.line + new.line - old_span.line;
+                span.start = (span.start as isize + delta) as usize;
+                span.end = (span.end as isize + delta) as usize;
+            };
+            for token in &mut tokens[changed.end..] {
+                shift(&mut token.span);
+                token.part_spans.iter_mut().flatten().for_each(&shift);
+            }
+        }
+        Ok(changed)
+    }
+
+    fn is_stateful(&self) -> bool {
+        self.modes.len() > 1 || self.modes.iter().any(|mode| {
+            mode.keywords.iter().any(Keywords::is_contextual) || mode.matchers.iter().any(|m| {
+                m.guard.is_some() || m.action.is_some() || m.channel == Channel::Trivia
+            })
+        })
+    }
+}
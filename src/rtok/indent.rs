@@ -0,0 +1,119 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use rtok::tokenizer::{Tokenizer, Token, TokenTypeId, Span, TokenizeError};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutError<K = TokenTypeId> {
+    Tokenize(TokenizeError<K>),
+    // a line at `span` is indented by `width`, which isn't one of the open
+    // indentation `levels`
+    Dedent { span: Span, width: usize, levels: Vec<usize> },
+}
+
+impl <K: fmt::Debug> fmt::Display for LayoutError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LayoutError::Tokenize(e) => write!(f, "{}", e),
+            LayoutError::Dedent { span, width, levels } =>
+                write!(f, "inconsistent dedent to width {} at line {}, column {} (byte {}); open levels: {:?}",
+                       width, span.line, span.column, span.start, levels),
+        }
+    }
+}
+
+impl <K: fmt::Debug> Error for LayoutError<K> {}
+
+impl <K> From<TokenizeError<K>> for LayoutError<K> {
+    fn from(e: TokenizeError<K>) -> LayoutError<K> {
+        LayoutError::Tokenize(e)
+    }
+}
+
+// Turns line starts and indentation into tokens of their own, like Python does.
+// A line's indentation is the column of its first token, so tabs count as one
+// column each. Lines without tokens, e.g. ones with only skipped or trivia
+// matches, don't count.
+pub struct Layout<K = TokenTypeId> {
+    newline: K,
+    indent: K,
+    dedent: K,
+}
+
+impl <K: Copy> Layout<K> {
+    pub fn new(newline: K, indent: K, dedent: K) -> Layout<K> {
+        Layout { newline, indent, dedent }
+    }
+
+    // Adds a NEWLINE token after every line with tokens, an INDENT token before
+    // each line indented deeper than the one before and a DEDENT token for each
+    // level a line closes. The added tokens are empty.
+    pub fn apply<'a>(&self, tokens: Vec<Token<'a, K>>, input: &'a str) -> Result<Vec<Token<'a, K>>, LayoutError<K>> {
+        let names = Arc::new(vec![None]);
+        let synthetic = |typ, at: Span| Token {
+            typ,
+            parts: vec![Some(&input[at.start..at.start])],
+            span: Span { end: at.start, ..at },
+            part_spans: vec![Some(Span { end: at.start, ..at })],
+            names: names.clone(),
+            leading_trivia: Vec::new(),
+            trailing_trivia: Vec::new(),
+        };
+
+        let mut out = Vec::with_capacity(tokens.len());
+        let mut levels = vec![0];
+        // where the previous token ends
+        let mut last : Option<Span> = None;
+
+        for token in tokens {
+            let starts_line = last.is_none_or(|end| token.span.line > end.line);
+            if starts_line {
+                if let Some(end) = last {
+                    out.push(synthetic(self.newline, end));
+                }
+
+                let width = token.span.column - 1;
+                if width > *levels.last().unwrap() {
+                    levels.push(width);
+                    out.push(synthetic(self.indent, token.span));
+                }
+                while width < *levels.last().unwrap() {
+                    levels.pop();
+                    out.push(synthetic(self.dedent, token.span));
+                }
+                if width != *levels.last().unwrap() {
+                    return Err(LayoutError::Dedent { span: token.span, width, levels });
+                }
+            }
+
+            let text = &input[token.span.start..token.span.end];
+            let column = match text.rfind('\n') {
+                Some(nl) => text[nl + 1..].chars().count() + 1,
+                None => token.span.column + text.chars().count(),
+            };
+            last = Some(Span {
+                start: token.span.end,
+                end: token.span.end,
+                line: token.span.line + text.matches('\n').count(),
+                column,
+            });
+            out.push(token);
+        }
+
+        if let Some(end) = last {
+            out.push(synthetic(self.newline, end));
+            for _ in 1..levels.len() {
+                out.push(synthetic(self.dedent, end));
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl <K: Copy + PartialEq> Tokenizer<K> {
+    pub fn tokenize_layout<'a>(&self, input: &'a str, layout: &Layout<K>) -> Result<Vec<Token<'a, K>>, LayoutError<K>> {
+        let tokens = self.tokenize(input)?;
+        layout.apply(tokens, input)
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use rtok::tokenizer::{Tokenizer, TokenTypeId, INITIAL_MODE};
+
+type Context<K> = Box<dyn Fn(Option<K>) -> bool>;
+
+struct Keyword<K> {
+    to_type: K,
+    // for soft keywords, whether the type of the previous significant token lets
+    // the word be a keyword
+    context: Option<Context<K>>,
+}
+
+// Reserved words that retag tokens of `ident_type` once they are matched, so one
+// identifier pattern covers keywords too. Words are looked up in a hash table by
+// the token's whole text.
+pub struct Keywords<K = TokenTypeId> {
+    ident_type: K,
+    words: HashMap<String, Keyword<K>>,
+    case_insensitive: bool,
+}
+
+impl <K: Copy + PartialEq> Keywords<K> {
+    pub fn new(ident_type: K) -> Keywords<K> {
+        Keywords { ident_type, words: HashMap::new(), case_insensitive: false }
+    }
+
+    // Makes `IF`, `If` and `if` the same keyword.
+    pub fn case_insensitive(mut self) -> Keywords<K> {
+        self.case_insensitive = true;
+        self.words = self.words.drain().map(|(word, keyword)| (word.to_lowercase(), keyword)).collect();
+        self
+    }
+
+    pub fn keyword(self, word: &str, to_type: K) -> Keywords<K> {
+        self.insert(word, Keyword { to_type, context: None })
+    }
+
+    // A word that is only a keyword where `context` accepts the type of the
+    // previous significant token (`None` at the start of the input), and an
+    // identifier everywhere else.
+    pub fn soft_keyword<F>(self, word: &str, to_type: K, context: F) -> Keywords<K>
+    where F: 'static + Fn(Option<K>) -> bool {
+        self.insert(word, Keyword { to_type, context: Some(Box::new(context)) })
+    }
+
+    fn insert(mut self, word: &str, keyword: Keyword<K>) -> Keywords<K> {
+        let word = if self.case_insensitive { word.to_lowercase() } else { word.to_string() };
+        self.words.insert(word, keyword);
+        self
+    }
+
+    pub(crate) fn is_contextual(&self) -> bool {
+        self.words.values().any(|keyword| keyword.context.is_some())
+    }
+
+    // The type a token of type `typ` with text `text` gets, if it is a keyword.
+    pub(crate) fn retag(&self, typ: K, text: &str, previous: Option<K>) -> Option<K> {
+        if typ != self.ident_type {
+            return None;
+        }
+        let keyword = if self.case_insensitive {
+            self.words.get(&text.to_lowercase())
+        } else {
+            self.words.get(text)
+        }?;
+        match keyword.context {
+            Some(ref context) if !context(previous) => None,
+            _ => Some(keyword.to_type),
+        }
+    }
+}
+
+impl <K: Copy + PartialEq> Tokenizer<K> {
+    pub fn add_keywords(&mut self, keywords: Keywords<K>) {
+        self.add_keywords_to(INITIAL_MODE, keywords);
+    }
+
+    // Keywords only apply to tokens matched while `mode` is the current mode. When
+    // several tables retag the same token, the one added first wins.
+    pub fn add_keywords_to(&mut self, mode: &str, keywords: Keywords<K>) {
+        self.mode_mut(mode).keywords.push(keywords);
+    }
+}
@@ -0,0 +1,111 @@
+// What a column counts. Editor protocols such as LSP count UTF-16 code units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnUnit {
+    Byte,
+    Char,
+    Utf16,
+}
+
+impl ColumnUnit {
+    // Length of a char of `len` bytes in this unit.
+    fn width(self, len: usize) -> usize {
+        match self {
+            ColumnUnit::Byte => len,
+            ColumnUnit::Char => 1,
+            ColumnUnit::Utf16 => if len == 4 { 2 } else { 1 },
+        }
+    }
+}
+
+// A line and column, both 1-based like in a `Span`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+// Maps byte offsets of an input to lines and columns and back. Lines are found by
+// binary search over where they start; columns other than bytes only need the
+// non-ASCII chars of the line, which are kept sorted the same way.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineIndex {
+    len: usize,
+    line_starts: Vec<usize>,
+    // offset and length in bytes of every non-ASCII char
+    wide: Vec<(usize, usize)>,
+}
+
+impl LineIndex {
+    pub fn new(input: &str) -> LineIndex {
+        let mut line_starts = vec![0];
+        let mut wide = Vec::new();
+        for (i, c) in input.char_indices() {
+            if c == '\n' {
+                line_starts.push(i + 1);
+            } else if !c.is_ascii() {
+                wide.push((i, c.len_utf8()));
+            }
+        }
+        LineIndex { len: input.len(), line_starts, wide }
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    // Byte range of line `line` (1-based), without its line break.
+    pub fn line_range(&self, line: usize) -> Option<(usize, usize)> {
+        let start = *self.line_starts.get(line.checked_sub(1)?)?;
+        let end = self.line_starts.get(line).map_or(self.len, |next| next - 1);
+        Some((start, end))
+    }
+
+    // `None` if `offset` is past the end of the input or inside a char.
+    pub fn position(&self, offset: usize, unit: ColumnUnit) -> Option<Position> {
+        if offset > self.len {
+            return None;
+        }
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+        let start = self.line_starts[line - 1];
+
+        let mut column = offset - start;
+        for &(at, len) in self.wide_in(start, offset) {
+            if at + len > offset {
+                return None;
+            }
+            column = column - len + unit.width(len);
+        }
+        Some(Position { line, column: column + 1 })
+    }
+
+    // `None` if the position is past the end of its line or inside a char.
+    pub fn offset(&self, position: Position, unit: ColumnUnit) -> Option<usize> {
+        let (start, end) = self.line_range(position.line)?;
+        let column = position.column.checked_sub(1)?;
+
+        // bytes the wide chars before the column take beyond their width
+        let mut extra = 0;
+        for &(at, len) in self.wide_in(start, end) {
+            let before = at - start - extra;
+            if before >= column {
+                break;
+            }
+            if before + unit.width(len) > column {
+                return None;
+            }
+            extra += len - unit.width(len);
+        }
+
+        let offset = start + column + extra;
+        if offset > end {
+            return None;
+        }
+        Some(offset)
+    }
+
+    fn wide_in(&self, start: usize, end: usize) -> &[(usize, usize)] {
+        let from = self.wide.partition_point(|&(at, _)| at < start);
+        let to = self.wide.partition_point(|&(at, _)| at < end);
+        &self.wide[from..to]
+    }
+}
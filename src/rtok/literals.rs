@@ -0,0 +1,68 @@
+// A byte trie over the literal matchers of a mode, so finding the longest
+// literal at the current position takes one walk instead of one comparison per
+// literal.
+pub(crate) struct Trie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Default)]
+struct Node {
+    // sorted by byte
+    next: Vec<(u8, usize)>,
+    // the matchers whose literal ends here, in the order they were added
+    matchers: Vec<usize>,
+}
+
+impl Trie {
+    pub(crate) fn new() -> Trie {
+        Trie { nodes: vec![Node::default()] }
+    }
+
+    pub(crate) fn insert(&mut self, literal: &str, matcher: usize) {
+        let mut node = 0;
+        for &b in literal.as_bytes() {
+            node = match self.nodes[node].next.binary_search_by_key(&b, |e| e.0) {
+                Ok(i) => self.nodes[node].next[i].1,
+                Err(i) => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].next.insert(i, (b, child));
+                    child
+                }
+            };
+        }
+        self.nodes[node].matchers.push(matcher);
+    }
+
+    // The matcher with the longest literal `input` starts with among those
+    // `enabled` accepts, the first one added if several have that literal.
+    pub(crate) fn longest<F: Fn(usize) -> bool>(&self, input: &str, enabled: F) -> Option<usize> {
+        let first = |node: usize| self.nodes[node].matchers.iter().cloned().find(|&m| enabled(m));
+        let mut node = 0;
+        let mut found = first(0);
+        for &b in input.as_bytes() {
+            node = match self.nodes[node].next.binary_search_by_key(&b, |e| e.0) {
+                Ok(i) => self.nodes[node].next[i].1,
+                Err(_) => break,
+            };
+            found = first(node).or(found);
+        }
+        found
+    }
+
+    // Whether all of `input` is the start of a longer literal.
+    pub(crate) fn extends(&self, input: &str) -> bool {
+        let mut node = 0;
+        for &b in input.as_bytes() {
+            node = match self.nodes[node].next.binary_search_by_key(&b, |e| e.0) {
+                Ok(i) => self.nodes[node].next[i].1,
+                Err(_) => return false,
+            };
+        }
+        !self.nodes[node].next.is_empty()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.nodes.len() == 1 && self.nodes[0].matchers.is_empty()
+    }
+}
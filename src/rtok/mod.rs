@@ -1,4 +1,14 @@
 pub mod tokenizer;
+pub mod stream;
+pub mod builder;
+pub mod analyze;
+pub mod keywords;
+mod literals;
+mod partial;
+pub mod bytes;
+pub mod indent;
+pub mod incremental;
+pub mod lines;
 
 #[macro_use]
 pub mod parser;
@@ -5,14 +5,16 @@ pub enum ParseValue<T,N> {
     Reduced(N),
 }
 
+type RuleChecker<T,N> = Box<dyn Fn(&Vec<ParseValue<T,N>>) -> bool>;
+type RuleReduction<T,N> = Box<dyn Fn(&mut Vec<ParseValue<T,N>>) -> Result<ParseValue<T,N>, ParseError>>;
+
 pub struct Parser<T,N> {
 
     input: Vec<T>,
     pstack: Vec<ParseValue<T,N>>,
     pub output: Vec<N>,
 
-    reductions: Vec<(Box<Fn(&Vec<ParseValue<T,N>>) -> bool>, 
-                     Box<Fn(&mut Vec<ParseValue<T,N>>) -> Result<ParseValue<T,N>, ParseError>>)>
+    reductions: Vec<(RuleChecker<T,N>, RuleReduction<T,N>)>
 }
 
 enum ParseAction {
@@ -22,6 +24,7 @@ enum ParseAction {
 }
 
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ParseError {
     EOF, NoActions, InvalidReduction(usize), InvalidToken, NotImpl
 }
@@ -33,7 +36,7 @@ impl <T,N> Parser<T,N> {
     }
 
     pub fn push_input(&mut self, new_input: Vec<T>) {
-        let mut new_vec = new_input;
+        let new_vec = new_input;
         // new_vec.append(&mut self.input);
         self.input = new_vec;
     }
@@ -64,7 +67,7 @@ impl <T,N> Parser<T,N> {
             .nth(0) {
                 ParseAction::Reduce(idx)
         }
-        else if self.input.len() > 0 {
+        else if !self.input.is_empty() {
             ParseAction::Shift
         } else {
             ParseAction::Stop
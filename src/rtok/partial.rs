@@ -0,0 +1,119 @@
+use regex_syntax::ParserBuilder;
+use regex_syntax::hir::{Hir, HirKind, Group, GroupKind, Anchor, Repetition, RepetitionKind, RepetitionRange};
+
+// Matches exactly the text that is the start of some match of `pattern`. A stream
+// whose unread input is such a start has to read on, since more input may still
+// turn it into a (longer) match.
+//
+// `loose` is for a caller's compiled regex whose settings can't be read back: the
+// pattern is parsed both with and without `(?x)`, ignoring case and with `.`
+// matching line breaks, so the result covers whatever the regex was built with.
+// `None` if the pattern can't be parsed that way.
+pub(crate) fn prefixes(pattern: &str, loose: bool) -> Option<Hir> {
+    let parse = |extended: bool| ParserBuilder::new()
+        .ignore_whitespace(extended)
+        .case_insensitive(loose)
+        .dot_matches_new_line(loose)
+        .build()
+        .parse(pattern)
+        .ok();
+    let parsed : Vec<Hir> = if loose { vec![parse(false), parse(true)] } else { vec![parse(false)] }
+        .into_iter()
+        .flatten()
+        .collect();
+    if parsed.is_empty() {
+        return None;
+    }
+
+    Some(alternation(parsed.iter().map(prefix).collect()))
+}
+
+// `prefixes` matching all of the input.
+pub(crate) fn exactly(prefixes: Hir) -> String {
+    Hir::concat(vec![Hir::anchor(Anchor::StartText), group(prefixes), Hir::anchor(Anchor::EndText)]).to_string()
+}
+
+// Any of `prefixes` matching up to the end of the input.
+pub(crate) fn ending(prefixes: Vec<Hir>) -> String {
+    Hir::concat(vec![group(alternation(prefixes)), Hir::anchor(Anchor::EndText)]).to_string()
+}
+
+// Every start of a match of `hir`. Zero-width assertions are dropped, which only
+// lets more through.
+fn prefix(hir: &Hir) -> Hir {
+    match *hir.kind() {
+        HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => Hir::empty(),
+        HirKind::Literal(_) | HirKind::Class(_) => optional(hir.clone()),
+        HirKind::Group(ref g) => prefix(&g.hir),
+        HirKind::Alternation(ref alts) => alternation(alts.iter().map(prefix).collect()),
+        // a start of `a b c` is a start of `a`, or all of `a` and a start of `b c`
+        HirKind::Concat(ref parts) => parts.iter().rev().fold(Hir::empty(), |rest, part| {
+            let first = prefix(part);
+            if is_empty(&first) {
+                rest
+            } else if is_empty(&rest) {
+                first
+            } else {
+                alternation(vec![first, Hir::concat(vec![group(plain(part)), group(rest)])])
+            }
+        }),
+        // a start of `r{m,n}` is up to n - 1 whole `r` and a start of one more
+        HirKind::Repetition(ref rep) => {
+            let more = match rep.kind {
+                RepetitionKind::ZeroOrOne => Some(0),
+                RepetitionKind::Range(RepetitionRange::Exactly(n)) |
+                RepetitionKind::Range(RepetitionRange::Bounded(_, n)) => Some(n.saturating_sub(1)),
+                RepetitionKind::ZeroOrMore |
+                RepetitionKind::OneOrMore |
+                RepetitionKind::Range(RepetitionRange::AtLeast(_)) => None,
+            };
+            let last = prefix(&rep.hir);
+            if is_empty(&last) || more == Some(0) {
+                return last;
+            }
+            let kind = match more {
+                Some(n) => RepetitionKind::Range(RepetitionRange::Bounded(0, n)),
+                None => RepetitionKind::ZeroOrMore,
+            };
+            let whole = Hir::repetition(Repetition { kind, greedy: true, hir: Box::new(group(plain(&rep.hir))) });
+            Hir::concat(vec![whole, group(last)])
+        },
+    }
+}
+
+// `hir` without capture groups, so several copies of it fit in one pattern.
+fn plain(hir: &Hir) -> Hir {
+    match *hir.kind() {
+        HirKind::Group(ref g) => group(plain(&g.hir)),
+        HirKind::Alternation(ref alts) => Hir::alternation(alts.iter().map(plain).collect()),
+        HirKind::Concat(ref parts) => Hir::concat(parts.iter().map(plain).collect()),
+        HirKind::Repetition(ref rep) => Hir::repetition(Repetition {
+            kind: rep.kind.clone(),
+            greedy: rep.greedy,
+            hir: Box::new(plain(&rep.hir)),
+        }),
+        _ => hir.clone(),
+    }
+}
+
+fn group(hir: Hir) -> Hir {
+    Hir::group(Group { kind: GroupKind::NonCapturing, hir: Box::new(hir) })
+}
+
+fn optional(hir: Hir) -> Hir {
+    Hir::repetition(Repetition { kind: RepetitionKind::ZeroOrOne, greedy: true, hir: Box::new(hir) })
+}
+
+// Every start already includes the empty one, so empty branches can go.
+fn alternation(alts: Vec<Hir>) -> Hir {
+    let alts : Vec<Hir> = alts.into_iter().map(group).filter(|alt| !is_empty(alt)).collect();
+    Hir::alternation(alts)
+}
+
+fn is_empty(hir: &Hir) -> bool {
+    match *hir.kind() {
+        HirKind::Empty => true,
+        HirKind::Group(ref g) => is_empty(&g.hir),
+        _ => false,
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::str;
+
+use rtok::tokenizer::{Tokenizer, TokenTypeId, OwnedToken, TokenizeError, Cursor, Step, TriviaAttacher};
+
+pub const DEFAULT_LOOKAHEAD: usize = 4096;
+
+#[derive(Debug)]
+pub enum StreamError<K = TokenTypeId> {
+    Io(io::Error),
+    Tokenize(TokenizeError<K>),
+}
+
+impl <K: fmt::Debug> fmt::Display for StreamError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamError::Io(e) => write!(f, "read error: {}", e),
+            StreamError::Tokenize(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl <K: fmt::Debug> Error for StreamError<K> {}
+
+impl <K> From<io::Error> for StreamError<K> {
+    fn from(e: io::Error) -> StreamError<K> {
+        StreamError::Io(e)
+    }
+}
+
+impl <K> From<TokenizeError<K>> for StreamError<K> {
+    fn from(e: TokenizeError<K>) -> StreamError<K> {
+        StreamError::Tokenize(e)
+    }
+}
+
+// Lexes a reader chunk by chunk. A match is only accepted once at least `lookahead`
+// bytes past its start have been read (or the reader is exhausted), and it is
+// retried with more input whenever some matcher's match runs into the end of what
+// has been read so far, or all of it could still be the start of a match, since
+// the winner may then still change.
+pub struct ReadTokens<'t, R, K: 't = TokenTypeId> {
+    tokenizer: &'t Tokenizer<K>,
+    reader: R,
+    buffer: String,
+    incomplete: Vec<u8>,
+    pos: usize,
+    cursor: Cursor<K>,
+    trivia: TriviaAttacher<OwnedToken<K>>,
+    ready: VecDeque<OwnedToken<K>>,
+    lookahead: usize,
+    eof: bool,
+    error: Option<StreamError<K>>,
+    done: bool,
+}
+
+impl <K: Copy + PartialEq> Tokenizer<K> {
+    pub fn read_tokens<'t, R: BufRead>(&'t self, reader: R) -> ReadTokens<'t, R, K> {
+        ReadTokens {
+            tokenizer: self,
+            reader,
+            buffer: String::new(),
+            incomplete: Vec::new(),
+            pos: 0,
+            cursor: Cursor::start(),
+            trivia: TriviaAttacher::new(),
+            ready: VecDeque::new(),
+            lookahead: DEFAULT_LOOKAHEAD,
+            eof: false,
+            error: None,
+            done: false,
+        }
+    }
+}
+
+impl <'t, R: BufRead, K: Copy + PartialEq> ReadTokens<'t, R, K> {
+    pub fn with_lookahead(mut self, lookahead: usize) -> ReadTokens<'t, R, K> {
+        self.lookahead = lookahead;
+        self
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        self.buffer.drain(..self.pos);
+        self.pos = 0;
+
+        let read = {
+            let chunk = self.reader.fill_buf()?;
+            self.incomplete.extend_from_slice(chunk);
+            chunk.len()
+        };
+        self.reader.consume(read);
+        self.eof = read == 0;
+
+        // a chunk may end in the middle of a char, keep those bytes for the next read
+        let valid = match str::from_utf8(&self.incomplete) {
+            Ok(s) => s.len(),
+            Err(ref e) if e.error_len().is_none() && !self.eof => e.valid_up_to(),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+        self.buffer.push_str(str::from_utf8(&self.incomplete[..valid]).unwrap());
+        self.incomplete.drain(..valid);
+        Ok(())
+    }
+
+    fn next_token(&mut self) -> Result<Option<OwnedToken<K>>, StreamError<K>> {
+        loop {
+            if let Some(token) = self.ready.pop_front() {
+                return Ok(Some(token));
+            }
+            // an empty buffer is only the end of the input once the reader says so
+            if !self.eof && self.buffer.len() - self.pos < self.lookahead.max(1) {
+                self.fill()?;
+                continue;
+            }
+
+            let current = &self.buffer[self.pos..];
+            if current.is_empty() {
+                return Ok(self.trivia.finish());
+            }
+
+            match self.tokenizer.step(&mut self.cursor, current, self.eof) {
+                Step::Token(token, consumed, channel) => {
+                    let owned = OwnedToken::from(token);
+                    self.pos += consumed;
+                    if let Some(token) = self.trivia.push(owned, channel) {
+                        return Ok(Some(token));
+                    }
+                },
+                Step::Tokens(tokens, consumed, channel) => {
+                    self.pos += consumed;
+                    for token in tokens {
+                        self.ready.extend(self.trivia.push(OwnedToken::from(token), channel));
+                    }
+                },
+                Step::NeedMore => self.fill()?,
+                Step::Failed(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl <'t, R: BufRead, K: Copy + PartialEq> Iterator for ReadTokens<'t, R, K> {
+    type Item = Result<OwnedToken<K>, StreamError<K>>;
+
+    fn next(&mut self) -> Option<Result<OwnedToken<K>, StreamError<K>>> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        if self.done {
+            return None;
+        }
+
+        match self.next_token() {
+            Ok(Some(token)) => Some(Ok(token)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                // hand out the token held back for trivia before the error
+                self.done = true;
+                match self.trivia.finish() {
+                    Some(token) => {
+                        self.error = Some(e);
+                        Some(Ok(token))
+                    },
+                    None => Some(Err(e)),
+                }
+            }
+        }
+    }
+}
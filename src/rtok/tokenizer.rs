@@ -1,54 +1,86 @@
 extern crate regex;
 
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::error::Error;
+use std::cell::OnceCell;
+use std::sync::Arc;
 
 pub mod postproc {
 
     use std::collections::HashMap;
+    use std::hash::Hash;
     use rtok::tokenizer::{Token, TokenTypeId};
 
     #[derive(Debug)]
     #[derive(PartialEq)]
-    pub struct PostprocErr {
+    pub struct PostprocErr<K = TokenTypeId> {
         error: String,
-        on_type: TokenTypeId,
+        on_type: K,
     }
 
-    impl PostprocErr {
-        pub fn new(token_type: TokenTypeId, error: String) -> PostprocErr {
+    impl <K: Copy> PostprocErr<K> {
+        pub fn new(token_type: K, error: String) -> PostprocErr<K> {
             PostprocErr { on_type: token_type, error }
         }
 
         #[allow(dead_code)]
-        pub fn make<T>(token_type: TokenTypeId, error: String) -> Result<T,PostprocErr> {
+        pub fn make<T>(token_type: K, error: String) -> Result<T,PostprocErr<K>> {
             Err(PostprocErr::new(token_type, error))
         }
+
+        pub fn on_type(&self) -> K {
+            self.on_type
+        }
+
+        pub fn error(&self) -> &str {
+            &self.error
+        }
+    }
+
+    type PostprocFn<T, K, S> = Box<dyn Fn(Token<K, S>) -> T>;
+
+    // `S` is the text type of the tokens, `str` or `[u8]` for a `ByteTokenizer`.
+    pub struct BasicPostProcessor<T, K = TokenTypeId, S: ?Sized = str> {
+        postprocfns: HashMap<K, PostprocFn<T, K, S>>
     }
 
-    pub struct BasicPostProcessor<T> {
-        postprocfns: HashMap<TokenTypeId, Box<Fn(Token) -> T>>
+    pub trait PostProcessor<T, K = TokenTypeId, S: ?Sized = str> {
+        fn run_on(&self, t: Token<K, S>) -> Result<T, PostprocErr<K>>;
     }
 
-    pub trait PostProcessor<T> {
-        fn run_on(&self, t: Token) -> Result<T, PostprocErr>;
+    impl <T, K: Eq + Hash> BasicPostProcessor<T, K> {
+        pub fn new() -> BasicPostProcessor<T, K> {
+            BasicPostProcessor { postprocfns: HashMap::new() }
+        }
     }
 
-    impl <T> BasicPostProcessor<T> {
-        pub fn new() -> BasicPostProcessor<T> {
+    impl <T, K: Eq + Hash> BasicPostProcessor<T, K, [u8]> {
+        pub fn for_bytes() -> BasicPostProcessor<T, K, [u8]> {
             BasicPostProcessor { postprocfns: HashMap::new() }
         }
-        
-        pub fn add_postprocfn_boxed(&mut self, for_id: TokenTypeId, postprocfn: Box<Fn(Token) -> T>) -> () {
+    }
+
+    impl <T, K: Eq + Hash, S: ?Sized> BasicPostProcessor<T, K, S> {
+        pub fn add_postprocfn_boxed(&mut self, for_id: K, postprocfn: PostprocFn<T, K, S>) {
             self.postprocfns.insert(for_id, postprocfn);
         }
 
-        pub fn add_postprocfn<F>(&mut self, for_id: TokenTypeId, postprocfn: F) -> ()
-        where F : 'static + Fn(Token) -> T {
+        pub fn add_postprocfn<F>(&mut self, for_id: K, postprocfn: F)
+        where F : 'static + Fn(Token<K, S>) -> T {
             self.add_postprocfn_boxed(for_id, Box::new(postprocfn));
         }
     }
 
-    impl <T> PostProcessor<T> for BasicPostProcessor<T> {
-        fn run_on(&self, t: Token) -> Result<T, PostprocErr> {
+    impl <T, K: Eq + Hash> Default for BasicPostProcessor<T, K> {
+        fn default() -> BasicPostProcessor<T, K> {
+            BasicPostProcessor::new()
+        }
+    }
+
+    impl <T, K: Copy + Eq + Hash, S: ?Sized> PostProcessor<T, K, S> for BasicPostProcessor<T, K, S> {
+        fn run_on(&self, t: Token<K, S>) -> Result<T, PostprocErr<K>> {
             if let Some(postprocfn) = self.postprocfns.get(&t.typ) {
                 Ok(postprocfn(t))
             } else {
@@ -57,8 +89,8 @@ pub mod postproc {
         }
     }
 
-    impl <T> PostProcessor<T> for BasicPostProcessor<Result<T, PostprocErr>> {
-        fn run_on(&self, t: Token) -> Result<T, PostprocErr> {
+    impl <T, K: Copy + Eq + Hash, S: ?Sized> PostProcessor<T, K, S> for BasicPostProcessor<Result<T, PostprocErr<K>>, K, S> {
+        fn run_on(&self, t: Token<K, S>) -> Result<T, PostprocErr<K>> {
             if let Some(postprocfn) = self.postprocfns.get(&t.typ) {
                 postprocfn(t)
             } else {
@@ -68,113 +100,1209 @@ pub mod postproc {
     }
 }
 
-use regex::{Regex, Captures};
+use regex::{Regex, RegexSet};
+use regex_syntax::ParserBuilder;
+use regex_syntax::hir::{Hir, Anchor, Group, GroupKind};
+use self::postproc::PostprocErr;
+use rtok::keywords::Keywords;
+use rtok::literals::Trie;
+use rtok::partial;
 
 pub type TokenTypeId = i32;
 
+// Byte range of a piece of input together with the line and column (both 1-based,
+// column counted in chars) where it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Location {
+    fn start() -> Location {
+        Location { offset: 0, line: 1, column: 1 }
+    }
+
+    fn advance(self, text: &str) -> Location {
+        let mut loc = self;
+        for c in text.chars() {
+            if c == '\n' {
+                loc.line += 1;
+                loc.column = 1;
+            } else {
+                loc.column += 1;
+            }
+        }
+        loc.offset += text.len();
+        loc
+    }
+
+    fn span_to(self, end: usize) -> Span {
+        Span { start: self.offset, end, line: self.line, column: self.column }
+    }
+}
+
+// `leading_trivia` and `trailing_trivia` hold the tokens of trivia matchers around
+// this one. Trailing trivia runs up to and including the first trivia token with a
+// line break; anything after that leads the next token.
+//
+// `names` holds the name of each capture group in `parts` (`None` for unnamed
+// ones), shared with every other token of the same matcher.
+//
+// `S` is the type of the text, `[u8]` for tokens of a `ByteTokenizer`.
 #[derive(Debug)]
-pub struct Token<'a> {
-    pub typ: TokenTypeId,
-    pub parts: Vec<Option<&'a str>>,
+pub struct Token<'a, K = TokenTypeId, S: ?Sized + 'a = str> {
+    pub typ: K,
+    pub parts: Vec<Option<&'a S>>,
+    pub span: Span,
+    pub part_spans: Vec<Option<Span>>,
+    pub names: CaptureNames,
+    pub leading_trivia: Vec<Token<'a, K, S>>,
+    pub trailing_trivia: Vec<Token<'a, K, S>>,
+}
+
+pub type CaptureNames = Arc<Vec<Option<String>>>;
+
+pub(crate) fn capture_names<'r, I: Iterator<Item = Option<&'r str>>>(names: I) -> CaptureNames {
+    Arc::new(names.map(|n| n.map(|n| n.to_string())).collect())
+}
+
+// `pattern` anchored to the start of the input. It goes through the parsed form
+// rather than being pasted into a bigger pattern, so `(?x)` comments and inline
+// flags keep their meaning. `bytes` parses it the way `regex::bytes` does.
+pub(crate) fn anchored(pattern: &str, bytes: bool) -> Result<String, regex::Error> {
+    let hir = ParserBuilder::new()
+        .allow_invalid_utf8(bytes)
+        .build()
+        .parse(pattern)
+        .map_err(|e| regex::Error::Syntax(e.to_string()))?;
+    let group = Hir::group(Group { kind: GroupKind::NonCapturing, hir: Box::new(hir) });
+    Ok(Hir::concat(vec![Hir::anchor(Anchor::StartText), group]).to_string())
+}
+
+fn part_index<K: Copy>(typ: K, names: &CaptureNames, name: &str) -> Result<usize, PostprocErr<K>> {
+    names.iter()
+        .position(|n| n.as_ref().is_some_and(|n| n == name))
+        .ok_or_else(|| {
+            let known = names.iter().flat_map(|n| n.iter()).cloned().collect::<Vec<String>>();
+            PostprocErr::new(typ, format!("Failed to find capture group {:?}, named groups are: [{}]", name, known.join(", ")))
+        })
+}
+
+fn unmatched_part<K: Copy>(typ: K, name: &str) -> PostprocErr<K> {
+    PostprocErr::new(typ, format!("Capture group {:?} did not take part in the match", name))
+}
+
+impl <'a, K: Copy, S: ?Sized> Token<'a, K, S> {
+    // Text of the capture group called `name`.
+    pub fn part(&self, name: &str) -> Result<&'a S, PostprocErr<K>> {
+        let idx = part_index(self.typ, &self.names, name)?;
+        self.parts[idx].ok_or_else(|| unmatched_part(self.typ, name))
+    }
+
+    pub fn part_span(&self, name: &str) -> Result<Span, PostprocErr<K>> {
+        let idx = part_index(self.typ, &self.names, name)?;
+        self.part_spans[idx].ok_or_else(|| unmatched_part(self.typ, name))
+    }
+}
+
+impl <'a, K: Copy> Token<'a, K> {
+    // Builds the token for a match of `current` with the capture groups `groups`,
+    // given as byte ranges of `current`, which starts at `location`. Returns it
+    // along with the location right after the match.
+    fn from_match(typ: K, names: &CaptureNames, groups: &[Option<(usize, usize)>], location: Location, current: &'a str) -> (Token<'a, K>, Location) {
+        let (start, end) = groups[0].unwrap();
+        let begin = location.advance(&current[..start]);
+        let span = begin.span_to(location.offset + end);
+        let part_spans = groups.iter()
+            .map(|g| g.map(|(s, e)| location.advance(&current[..s]).span_to(location.offset + e)))
+            .collect();
+
+        let token = Token {
+            typ,
+            parts: groups.iter().map(|g| g.map(|(s, e)| &current[s..e])).collect(),
+            span,
+            part_spans,
+            names: names.clone(),
+            leading_trivia: Vec::new(),
+            trailing_trivia: Vec::new(),
+        };
+        (token, begin.advance(&current[start..end]))
+    }
+
+    // A token of type `typ` for bytes `start..end` of this token's text, e.g. one
+    // of the `>` of a `>>` split up in a rule action. It has no capture groups or
+    // trivia of its own.
+    pub fn sub_token(&self, typ: K, start: usize, end: usize) -> Token<'a, K> {
+        let text = self.parts[0].unwrap();
+        let location = Location { offset: self.span.start, line: self.span.line, column: self.span.column };
+        let span = location.advance(&text[..start]).span_to(self.span.start + end);
+        Token {
+            typ,
+            parts: vec![Some(&text[start..end])],
+            span,
+            part_spans: vec![Some(span)],
+            names: Arc::new(vec![None]),
+            leading_trivia: Vec::new(),
+            trailing_trivia: Vec::new(),
+        }
+    }
+}
+
+// A token that owns its text, so it can outlive the input it was lexed from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedToken<K = TokenTypeId> {
+    pub typ: K,
+    pub parts: Vec<Option<String>>,
+    pub span: Span,
+    pub part_spans: Vec<Option<Span>>,
+    pub names: CaptureNames,
+    pub leading_trivia: Vec<OwnedToken<K>>,
+    pub trailing_trivia: Vec<OwnedToken<K>>,
+}
+
+impl <K: Copy> OwnedToken<K> {
+    pub fn part(&self, name: &str) -> Result<&str, PostprocErr<K>> {
+        let idx = part_index(self.typ, &self.names, name)?;
+        self.parts[idx].as_deref().ok_or_else(|| unmatched_part(self.typ, name))
+    }
+
+    pub fn part_span(&self, name: &str) -> Result<Span, PostprocErr<K>> {
+        let idx = part_index(self.typ, &self.names, name)?;
+        self.part_spans[idx].ok_or_else(|| unmatched_part(self.typ, name))
+    }
+
+    // A token borrowing from this one, e.g. to run a postprocessor on it.
+    pub fn as_token(&self) -> Token<'_, K> {
+        Token {
+            typ: self.typ,
+            parts: self.parts.iter().map(|p| p.as_deref()).collect(),
+            span: self.span,
+            part_spans: self.part_spans.clone(),
+            names: self.names.clone(),
+            leading_trivia: self.leading_trivia.iter().map(OwnedToken::as_token).collect(),
+            trailing_trivia: self.trailing_trivia.iter().map(OwnedToken::as_token).collect(),
+        }
+    }
+}
+
+impl <'a, K> Token<'a, K> {
+    // Copies the text of the token and its trivia out of the input.
+    pub fn into_owned(self) -> OwnedToken<K> {
+        OwnedToken::from(self)
+    }
+}
+
+impl <'a, K> From<Token<'a, K>> for OwnedToken<K> {
+    fn from(t: Token<'a, K>) -> OwnedToken<K> {
+        OwnedToken {
+            typ: t.typ,
+            parts: t.parts.into_iter().map(|p| p.map(|s| s.to_string())).collect(),
+            span: t.span,
+            part_spans: t.part_spans,
+            names: t.names,
+            leading_trivia: t.leading_trivia.into_iter().map(OwnedToken::from).collect(),
+            trailing_trivia: t.trailing_trivia.into_iter().map(OwnedToken::from).collect(),
+        }
+    }
+}
+
+pub(crate) trait HasTrivia: Sized {
+    fn ends_line(&self) -> bool;
+    fn leading_trivia(&mut self) -> &mut Vec<Self>;
+    fn trailing_trivia(&mut self) -> &mut Vec<Self>;
+}
+
+impl <'a, K> HasTrivia for Token<'a, K> {
+    fn ends_line(&self) -> bool {
+        self.parts[0].is_some_and(|s| s.contains('\n'))
+    }
+
+    fn leading_trivia(&mut self) -> &mut Vec<Token<'a, K>> {
+        &mut self.leading_trivia
+    }
+
+    fn trailing_trivia(&mut self) -> &mut Vec<Token<'a, K>> {
+        &mut self.trailing_trivia
+    }
+}
+
+impl <K> HasTrivia for OwnedToken<K> {
+    fn ends_line(&self) -> bool {
+        self.parts[0].as_ref().is_some_and(|s| s.contains('\n'))
+    }
+
+    fn leading_trivia(&mut self) -> &mut Vec<OwnedToken<K>> {
+        &mut self.leading_trivia
+    }
+
+    fn trailing_trivia(&mut self) -> &mut Vec<OwnedToken<K>> {
+        &mut self.trailing_trivia
+    }
 }
 
-pub struct Matcher {
-    pattern: Regex,
-    to_type: TokenTypeId,
+// Where the token of a matcher ends up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Channel {
+    Main,
+    Skip,
+    Trivia,
 }
 
-impl Matcher {
-    pub fn new(pattern: Regex, to_type: TokenTypeId) -> Matcher {
-        Matcher { pattern, to_type }
+// Holds back each significant token until the trivia following it is known, so
+// trivia can be attached to the tokens around it.
+pub(crate) struct TriviaAttacher<T> {
+    pending: Option<T>,
+    trailing_done: bool,
+    leading: Vec<T>,
+}
+
+impl <T: HasTrivia> TriviaAttacher<T> {
+    pub(crate) fn new() -> TriviaAttacher<T> {
+        TriviaAttacher { pending: None, trailing_done: false, leading: Vec::new() }
+    }
+
+    // Returns the previous significant token once `token` shows it is complete.
+    pub(crate) fn push(&mut self, mut token: T, channel: Channel) -> Option<T> {
+        match channel {
+            Channel::Skip => None,
+            Channel::Trivia => {
+                match self.pending {
+                    Some(ref mut pending) if !self.trailing_done => {
+                        self.trailing_done = token.ends_line();
+                        pending.trailing_trivia().push(token);
+                    },
+                    _ => self.leading.push(token),
+                }
+                None
+            },
+            Channel::Main => {
+                token.leading_trivia().append(&mut self.leading);
+                self.trailing_done = false;
+                self.pending.replace(token)
+            },
+        }
     }
+
+    // Returns the last significant token, which also gets any trivia left over.
+    // Trivia in input without significant tokens is dropped.
+    pub(crate) fn finish(&mut self) -> Option<T> {
+        self.pending.take().map(|mut token| {
+            token.trailing_trivia().append(&mut self.leading);
+            token
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenizeErrorKind<K = TokenTypeId> {
+    NoMatch,
+    // the winning matcher (for this type) matched the empty string, so lexing
+    // would never advance
+    EmptyMatch(K),
+    // a matcher tried to enter a mode that was never given any matchers
+    UnknownMode(String),
+    // a matcher tried to pop the last mode off the mode stack
+    PopLastMode,
+    // the custom matcher for this type said its match ends at a byte past the
+    // input or inside a char
+    BadMatchEnd(K, usize),
+}
+
+// Returned when lexing can't continue at `span`, whose text is `text`. `tried`
+// holds the type id and pattern of each matcher in the current mode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenizeError<K = TokenTypeId> {
+    pub kind: TokenizeErrorKind<K>,
+    pub span: Span,
+    pub text: String,
+    pub tried: Vec<(K, String)>,
+}
+
+impl <K: Copy> TokenizeError<K> {
+    fn no_match(matchers: &[Rule<K>], location: Location, rest: &str) -> TokenizeError<K> {
+        TokenizeError::new(TokenizeErrorKind::NoMatch, matchers, location, rest)
+    }
+
+    fn empty_match(matchers: &[Rule<K>], location: Location, rest: &str, typ: K) -> TokenizeError<K> {
+        TokenizeError::new(TokenizeErrorKind::EmptyMatch(typ), matchers, location, rest)
+    }
+
+    fn new(kind: TokenizeErrorKind<K>, matchers: &[Rule<K>], location: Location, rest: &str) -> TokenizeError<K> {
+        let len = rest.char_indices()
+            .skip(1)
+            .find(|&(i, c)| c.is_whitespace() || i >= 32)
+            .map_or(rest.len(), |(i, _)| i);
+
+        TokenizeError {
+            kind,
+            span: location.span_to(location.offset + len),
+            text: rest[..len].to_string(),
+            tried: matchers.iter().map(|m| (m.to_type, m.source.clone())).collect(),
+        }
+    }
+}
+
+impl <K: fmt::Debug> fmt::Display for TokenizeError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            TokenizeErrorKind::NoMatch =>
+                write!(f, "no matcher matched {:?}", self.text)?,
+            TokenizeErrorKind::EmptyMatch(typ) =>
+                write!(f, "matcher for type {:?} matched the empty string before {:?}", typ, self.text)?,
+            TokenizeErrorKind::UnknownMode(mode) =>
+                write!(f, "match {:?} switches to unknown mode {:?}", self.text, mode)?,
+            TokenizeErrorKind::PopLastMode =>
+                write!(f, "match {:?} pops the last mode", self.text)?,
+            TokenizeErrorKind::BadMatchEnd(typ, end) =>
+                write!(f, "matcher for type {:?} gave match end {}, which isn't a char boundary of {:?}", typ, end, self.text)?,
+        }
+        write!(f, " at line {}, column {} (byte {}); tried: ",
+               self.span.line, self.span.column, self.span.start)?;
+        let tried = self.tried.iter()
+            .map(|(typ, pattern)| format!("{:?} /{}/", typ, pattern))
+            .collect::<Vec<String>>()
+            .join(", ");
+        write!(f, "{}", tried)
+    }
+}
+
+impl <K: fmt::Debug> Error for TokenizeError<K> {}
+
+// What a matcher does to the tokenizer's mode stack when it wins.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModeChange {
+    Stay,
+    Push(String),
+    Pop,
+    Switch(String),
 }
 
-#[derive(PartialEq)]
+// A hand-written scanner for tokens a regex can't describe, like nested comments
+// or heredocs. Returns the length in bytes of the token at the very start of
+// `input`, if there is one; it has to end on a char boundary. When streaming,
+// `input` may end before the token does, see `ReadTokens`.
+pub trait Matcher {
+    fn match_len(&self, input: &str) -> Option<usize>;
+}
+
+impl <F: Fn(&str) -> Option<usize>> Matcher for F {
+    fn match_len(&self, input: &str) -> Option<usize> {
+        self(input)
+    }
+}
+
+enum Pattern {
+    // anchored when the rule was made, so it can go into the mode's set
+    Regex(Regex),
+    // the caller's own regex, used as it is
+    Compiled(Regex),
+    Literal(String),
+    Custom(Box<dyn Matcher>),
+}
+
+// A matcher together with what to do with its matches. A regex only ever matches
+// at the current position, whether or not it starts with `^`; `source` keeps the
+// pattern as written (or the name of a custom matcher) for error messages.
+pub struct Rule<K = TokenTypeId> {
+    pub(crate) source: String,
+    pattern: Pattern,
+    names: CaptureNames,
+    pub(crate) to_type: K,
+    pub(crate) mode_change: ModeChange,
+    pub(crate) channel: Channel,
+    pub(crate) priority: i32,
+    pub(crate) guard: Option<Guard<K>>,
+    pub(crate) action: Option<Action<K>>,
+}
+
+type Guard<K> = Box<dyn Fn(Option<K>) -> bool>;
+type Action<K> = Box<dyn for<'a> Fn(Token<'a, K>, &mut LexerState) -> Vec<Token<'a, K>>>;
+// index and end of the winning match, and the furthest end of any match
+type Picked = (Option<(usize, usize)>, usize);
+
+impl <K> Rule<K> {
+    // A rule for a regex the caller compiled, keeping any `RegexBuilder` settings.
+    // Those can't be read back out of the regex, so it stays out of the mode's
+    // `RegexSet` and is searched on its own at every position, only counting a
+    // match that starts there. `try_new` is faster for plain patterns.
+    pub fn new(pattern: Regex, to_type: K) -> Rule<K> {
+        let source = pattern.as_str().to_string();
+        let names = capture_names(pattern.capture_names());
+        Rule::with_pattern(source, Pattern::Compiled(pattern), names, to_type)
+    }
+
+    // A rule for the regex `pattern`, compiled anchored to the current position.
+    pub fn try_new(pattern: &str, to_type: K) -> Result<Rule<K>, regex::Error> {
+        let anchored = Regex::new(&anchored(pattern, false)?)?;
+        let names = capture_names(anchored.capture_names());
+        Ok(Rule::with_pattern(pattern.to_string(), Pattern::Regex(anchored), names, to_type))
+    }
+
+    // A rule for an exact string. All literal rules of a mode are looked up at
+    // once, and only the longest literal at the current position whose guard
+    // passes competes with the other matchers, whatever the priority.
+    pub fn literal(text: &str, to_type: K) -> Rule<K> {
+        Rule::with_pattern(text.to_string(), Pattern::Literal(text.to_string()), Arc::new(vec![None]), to_type)
+    }
+
+    // A rule for a hand-written matcher. Its tokens only have the one part, the
+    // whole match.
+    pub fn custom<M: 'static + Matcher>(name: &str, matcher: M, to_type: K) -> Rule<K> {
+        Rule::with_pattern(name.to_string(), Pattern::Custom(Box::new(matcher)), Arc::new(vec![None]), to_type)
+    }
+
+    fn with_pattern(source: String, pattern: Pattern, names: CaptureNames, to_type: K) -> Rule<K> {
+        Rule { source, pattern, names, to_type, mode_change: ModeChange::Stay, channel: Channel::Main, priority: 0, guard: None, action: None }
+    }
+
+    // Matches of a skip matcher are consumed but never produce tokens.
+    pub fn skip(mut self) -> Rule<K> {
+        self.channel = Channel::Skip;
+        self
+    }
+
+    // Matches of a trivia matcher don't show up in the token stream; they are
+    // attached to the significant tokens around them instead.
+    pub fn trivia(mut self) -> Rule<K> {
+        self.channel = Channel::Trivia;
+        self
+    }
+
+    // Only used by policies with `TieBreak::HighestPriority`; matchers start at 0.
+    pub fn with_priority(mut self, priority: i32) -> Rule<K> {
+        self.priority = priority;
+        self
+    }
+
+    // The rule only takes part where `guard` accepts the type of the previous
+    // significant token (`None` at the start of the input), e.g. to tell a regex
+    // literal from a division.
+    pub fn guard<F: 'static + Fn(Option<K>) -> bool>(mut self, guard: F) -> Rule<K> {
+        self.guard = Some(Box::new(guard));
+        self
+    }
+
+    // Runs `action` on every token of the rule, flex style. The tokens it returns
+    // replace the matched one: it can drop the match, retype it, or split it with
+    // `Token::sub_token`, and it can keep counts such as nesting depth in the
+    // `LexerState`. The returned tokens all go to the rule's channel.
+    pub fn action<F>(mut self, action: F) -> Rule<K>
+    where F: 'static + for<'a> Fn(Token<'a, K>, &mut LexerState) -> Vec<Token<'a, K>> {
+        self.action = Some(Box::new(action));
+        self
+    }
+
+    pub(crate) fn allows(&self, previous: Option<K>) -> bool {
+        self.guard.as_ref().is_none_or(|guard| guard(previous))
+    }
+
+    pub fn push_mode(mut self, mode: &str) -> Rule<K> {
+        self.mode_change = ModeChange::Push(mode.to_string());
+        self
+    }
+
+    pub fn pop_mode(mut self) -> Rule<K> {
+        self.mode_change = ModeChange::Pop;
+        self
+    }
+
+    pub fn switch_mode(mut self, mode: &str) -> Rule<K> {
+        self.mode_change = ModeChange::Switch(mode.to_string());
+        self
+    }
+
+    // Whether the pattern can match without consuming anything. Such a matcher is
+    // only usable when some other matcher wins over it wherever it would be empty.
+    pub fn matches_empty(&self) -> bool {
+        self.find("") == Ok(Some(0))
+    }
+
+    pub(crate) fn literal_text(&self) -> Option<&str> {
+        match self.pattern {
+            Pattern::Literal(ref text) => Some(text),
+            Pattern::Regex(_) | Pattern::Compiled(_) | Pattern::Custom(_) => None,
+        }
+    }
+
+    pub(crate) fn is_custom(&self) -> bool {
+        match self.pattern {
+            Pattern::Regex(_) | Pattern::Compiled(_) | Pattern::Literal(_) => false,
+            Pattern::Custom(_) => true,
+        }
+    }
+
+    // Whether both rules are sure to match the same text: two regexes made from
+    // the same pattern text, or two literals for the same string. A caller's own
+    // regex may have been built with other settings and a custom matcher's source
+    // is just a name, so those are never the same as anything.
+    pub(crate) fn same_pattern(&self, other: &Rule<K>) -> bool {
+        match (&self.pattern, &other.pattern) {
+            (&Pattern::Regex(_), &Pattern::Regex(_)) |
+            (&Pattern::Literal(_), &Pattern::Literal(_)) => self.source == other.source,
+            _ => false,
+        }
+    }
+
+    // End of the match at the start of `input`. A custom matcher's end that is
+    // past the end of `input` or inside a char comes back as the error.
+    pub(crate) fn find(&self, input: &str) -> Result<Option<usize>, usize> {
+        match self.pattern {
+            Pattern::Regex(ref regex) => Ok(regex.find(input).map(|m| m.end())),
+            Pattern::Compiled(ref regex) => Ok(regex.find(input).filter(|m| m.start() == 0).map(|m| m.end())),
+            Pattern::Literal(ref text) => Ok(if input.starts_with(text.as_str()) { Some(text.len()) } else { None }),
+            Pattern::Custom(ref matcher) => match matcher.match_len(input) {
+                Some(end) if end > input.len() || !input.is_char_boundary(end) => Err(end),
+                end => Ok(end),
+            },
+        }
+    }
+
+    // Byte range of each capture group of the match at the start of `input`.
+    fn groups(&self, input: &str) -> Vec<Option<(usize, usize)>> {
+        match self.pattern {
+            Pattern::Regex(ref regex) | Pattern::Compiled(ref regex) => regex.captures(input)
+                .filter(|c| c.get(0).unwrap().start() == 0)
+                .map(|c| c.iter().map(|g| g.map(|g| (g.start(), g.end()))).collect())
+                .unwrap_or_default(),
+            Pattern::Literal(_) | Pattern::Custom(_) => vec![self.find(input).unwrap_or(None).map(|end| (0, end))],
+        }
+    }
+}
+
+// How the winner is picked when several matchers match at the same position.
+// `First`, `Longest` and `Shortest` are shorthands for `Compound` policies.
+#[derive(Debug, Clone, PartialEq)]
 pub enum MatcherPriority {
     First,
     Longest,
     Shortest,
+    // applies each tie break in turn until one of them prefers a match; matches
+    // nothing tells apart go to the earliest matcher
+    Compound(Vec<TieBreak>),
 }
 
-pub struct Tokenizer {
-    matchers: Vec<Matcher>,
-    priority: MatcherPriority,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TieBreak {
+    Longest,
+    Shortest,
+    // the matcher with the highest `Rule::with_priority`
+    HighestPriority,
+    // the matcher added first; tie breaks after this one never get to decide
+    Earliest,
 }
 
-impl Tokenizer {
-    pub fn new(priority: MatcherPriority) -> Tokenizer {
-        Tokenizer { matchers: Vec::new(), priority: priority }
+impl MatcherPriority {
+    pub fn tie_breaks(&self) -> &[TieBreak] {
+        match self {
+            MatcherPriority::First => &[TieBreak::Earliest],
+            MatcherPriority::Longest => &[TieBreak::Longest, TieBreak::Earliest],
+            MatcherPriority::Shortest => &[TieBreak::Shortest, TieBreak::Earliest],
+            MatcherPriority::Compound(tie_breaks) => tie_breaks,
+        }
     }
 
-    pub fn make(priority: MatcherPriority, matchers: Vec<(&str, TokenTypeId)>) -> Tokenizer {
-        let mut tokenizer = Tokenizer::new(priority);
-        for (pattern,type_id) in matchers {
-            tokenizer.add_matcher(Matcher::new(Regex::new(pattern).unwrap(), type_id));
+    // Whether the first candidate always wins, whatever the others match.
+    pub(crate) fn first_wins(&self) -> bool {
+        self.tie_breaks().first() == Some(&TieBreak::Earliest)
+    }
+
+    // Compares two matches, given as their length and their matcher's priority,
+    // by the tie breaks up to `Earliest`. `Greater` means `a` is preferred; `Equal`
+    // leaves it to the order of the matchers.
+    pub(crate) fn compare(&self, a: (usize, i32), b: (usize, i32)) -> Ordering {
+        self.tie_breaks().iter()
+            .take_while(|&&t| t != TieBreak::Earliest)
+            .map(|t| match t {
+                TieBreak::Longest => a.0.cmp(&b.0),
+                TieBreak::Shortest => b.0.cmp(&a.0),
+                TieBreak::HighestPriority => a.1.cmp(&b.1),
+                TieBreak::Earliest => Ordering::Equal,
+            })
+            .find(|&o| o != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+
+    // Picks the winner among `matches`, given in matcher order as the index, the
+    // end and the priority of each matcher's match. Also returns the furthest end
+    // of any match that was looked at.
+    pub(crate) fn pick<I>(&self, matches: I) -> Picked
+    where I: Iterator<Item = (usize, usize, i32)> {
+        let mut best : Option<(usize, usize, i32)> = None;
+        let mut furthest = 0;
+
+        for (idx, end, priority) in matches {
+            furthest = furthest.max(end);
+
+            let better = match best {
+                Some((_, best_end, best_priority)) => self.compare((end, priority), (best_end, best_priority)) == Ordering::Greater,
+                None => true,
+            };
+            if better {
+                best = Some((idx, end, priority));
+            }
+            if self.first_wins() {
+                break;
+            }
         }
+
+        (best.map(|(idx, end, _)| (idx, end)), furthest)
+    }
+}
+
+// Name of the mode lexing starts in, and the one `add_matcher` and `make` add to.
+pub const INITIAL_MODE: &str = "INITIAL";
+
+// A named set of matchers, only active while it is on top of the mode stack.
+// They are compiled for lexing the first time they are needed, so adding
+// matchers one at a time doesn't compile the mode again each time.
+pub(crate) struct Mode<K> {
+    pub(crate) name: String,
+    pub(crate) matchers: Vec<Rule<K>>,
+    compiled: OnceCell<Compiled>,
+    pub(crate) keywords: Vec<Keywords<K>>,
+}
+
+// `set` holds the regex matchers, `in_set` their indices into `matchers`,
+// `literals` the literal ones and `each` the indices of the rest, which are tried
+// one by one. `error` says why the regex matchers couldn't go into one set, e.g.
+// because together they are too big; they are then all in `each`.
+//
+// `prefixes` holds for the regex matchers listed in `prefixed` a pattern matching
+// the starts of their matches (see `partial`); `unbounded` lists the regex
+// matchers that didn't get one and so may always match more. `reach` finds the
+// starts of a match of any matcher at the end of a text, `None` when some matcher
+// has no such pattern.
+struct Compiled {
+    set: RegexSet,
+    in_set: Vec<usize>,
+    literals: Trie,
+    each: Vec<usize>,
+    error: Option<regex::Error>,
+    prefixes: RegexSet,
+    prefixed: Vec<usize>,
+    unbounded: Vec<usize>,
+    reach: Option<Regex>,
+}
+
+impl Compiled {
+    fn new<K>(matchers: &[Rule<K>]) -> Compiled {
+        let mut patterns = Vec::new();
+        let mut in_set = Vec::new();
+        let mut literals = Trie::new();
+        let mut each = Vec::new();
+        for (idx, matcher) in matchers.iter().enumerate() {
+            match matcher.pattern {
+                Pattern::Regex(ref regex) => {
+                    patterns.push(regex.as_str());
+                    in_set.push(idx);
+                },
+                Pattern::Literal(ref text) => literals.insert(text, idx),
+                Pattern::Compiled(_) | Pattern::Custom(_) => each.push(idx),
+            }
+        }
+        // each pattern compiled on its own, but together they may still be too big
+        let (set, error) = match RegexSet::new(patterns) {
+            Ok(set) => (set, None),
+            Err(error) => {
+                each.append(&mut in_set);
+                each.sort();
+                (RegexSet::new(Vec::<&str>::new()).unwrap(), Some(error))
+            },
+        };
+
+        let mut prefixes = Vec::new();
+        let mut prefixed = Vec::new();
+        let mut unbounded = Vec::new();
+        let mut every = Some(Vec::new());
+        for (idx, matcher) in matchers.iter().enumerate() {
+            let prefix = match matcher.pattern {
+                Pattern::Regex(ref regex) => partial::prefixes(regex.as_str(), false),
+                Pattern::Compiled(ref regex) => partial::prefixes(regex.as_str(), true),
+                Pattern::Literal(ref text) => {
+                    every = every.and_then(|mut every| {
+                        every.push(partial::prefixes(&regex::escape(text), false)?);
+                        Some(every)
+                    });
+                    continue;
+                },
+                Pattern::Custom(_) => {
+                    every = None;
+                    continue;
+                },
+            };
+            match prefix {
+                Some(prefix) => {
+                    prefixes.push(partial::exactly(prefix.clone()));
+                    prefixed.push(idx);
+                    if let Some(ref mut every) = every {
+                        every.push(prefix);
+                    }
+                },
+                None => {
+                    unbounded.push(idx);
+                    every = None;
+                },
+            }
+        }
+        // a prefix pattern may grow past the size limit
+        let reach = every.and_then(|every| Regex::new(&partial::ending(every)).ok());
+        let prefixes = match RegexSet::new(&prefixes) {
+            Ok(set) => set,
+            Err(_) => {
+                unbounded.append(&mut prefixed);
+                unbounded.sort();
+                RegexSet::new(Vec::<&str>::new()).unwrap()
+            },
+        };
+
+        Compiled { set, in_set, literals, each, error, prefixes, prefixed, unbounded, reach }
+    }
+}
+
+impl <K> Mode<K> {
+    fn new(name: &str) -> Mode<K> {
+        Mode {
+            name: name.to_string(),
+            matchers: Vec::new(),
+            compiled: OnceCell::new(),
+            keywords: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, matchers: Vec<Rule<K>>) {
+        self.matchers.extend(matchers);
+        self.compiled = OnceCell::new();
+    }
+
+    fn compiled(&self) -> &Compiled {
+        self.compiled.get_or_init(|| Compiled::new(&self.matchers))
+    }
+
+    // Why the regex matchers couldn't be compiled into one set. They still work,
+    // only slower.
+    pub(crate) fn set_error(&self) -> Option<&regex::Error> {
+        self.compiled().error.as_ref()
+    }
+
+    // Whether a matcher `enabled` accepts could match past the end of `current`
+    // once more input is read.
+    fn may_extend<F: Fn(&Rule<K>) -> bool>(&self, current: &str, enabled: F) -> bool {
+        let compiled = self.compiled();
+        compiled.prefixes.matches(current).iter().map(|i| compiled.prefixed[i])
+            .chain(compiled.unbounded.iter().cloned())
+            .any(|idx| enabled(&self.matchers[idx]))
+            || compiled.literals.extends(current)
+    }
+
+    // The earliest offset in `text` from which a matcher could match on up to its
+    // end and maybe beyond, i.e. from where lexing may look past `text`.
+    pub(crate) fn reach(&self, text: &str) -> usize {
+        match self.compiled().reach {
+            Some(ref reach) => reach.find(text).map_or(text.len(), |m| m.start()),
+            None => 0,
+        }
+    }
+
+    // Indices of the matchers `enabled` accepts that may match at the start of
+    // `current`, in order.
+    fn candidates<F: Fn(&Rule<K>) -> bool>(&self, current: &str, enabled: F) -> Vec<usize> {
+        let compiled = self.compiled();
+        let mut candidates : Vec<usize> = compiled.set.matches(current).iter().map(|i| compiled.in_set[i]).collect();
+        if compiled.literals.is_empty() && compiled.each.is_empty() {
+            return candidates;
+        }
+        candidates.extend(compiled.literals.longest(current, |idx| enabled(&self.matchers[idx])));
+        candidates.extend(&compiled.each);
+        candidates.sort();
+        candidates
+    }
+}
+
+pub struct Tokenizer<K = TokenTypeId> {
+    pub(crate) modes: Vec<Mode<K>>,
+    pub(crate) priority: MatcherPriority,
+}
+
+// Named counters that rule actions share for the length of one input. A counter
+// that was never set is 0.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LexerState {
+    counters: HashMap<String, i64>,
+}
+
+impl LexerState {
+    pub fn get(&self, counter: &str) -> i64 {
+        self.counters.get(counter).cloned().unwrap_or(0)
+    }
+
+    pub fn set(&mut self, counter: &str, value: i64) {
+        self.counters.insert(counter.to_string(), value);
+    }
+
+    // Returns the new value.
+    pub fn add(&mut self, counter: &str, delta: i64) -> i64 {
+        let value = self.counters.entry(counter.to_string()).or_insert(0);
+        *value += delta;
+        *value
+    }
+}
+
+// Lexer state that has to survive between tokens: where we are, the mode stack,
+// the type of the last significant token and the counters of rule actions.
+pub(crate) struct Cursor<K> {
+    location: Location,
+    modes: Vec<usize>,
+    previous: Option<K>,
+    state: LexerState,
+}
+
+impl <K> Cursor<K> {
+    pub(crate) fn start() -> Cursor<K> {
+        Cursor { location: Location::start(), modes: vec![0], previous: None, state: LexerState::default() }
+    }
+
+    // A fresh cursor at the start of `span`.
+    pub(crate) fn at(span: Span) -> Cursor<K> {
+        let location = Location { offset: span.start, line: span.line, column: span.column };
+        Cursor { location, ..Cursor::start() }
+    }
+}
+
+pub(crate) enum Step<'a, K> {
+    // a token, the number of bytes of input it consumed and where it goes
+    Token(Token<'a, K>, usize, Channel),
+    // what a rule action made of a match
+    Tokens(Vec<Token<'a, K>>, usize, Channel),
+    // the input seen so far isn't enough to decide on the next token
+    NeedMore,
+    Failed(TokenizeError<K>),
+}
+
+impl <K: Copy + PartialEq> Tokenizer<K> {
+    pub fn new(priority: MatcherPriority) -> Tokenizer<K> {
+        Tokenizer { modes: vec![Mode::new(INITIAL_MODE)], priority }
+    }
+
+    // Panics if a pattern isn't a valid regex; see `builder` for a checked way to
+    // build a tokenizer.
+    pub fn make(priority: MatcherPriority, matchers: Vec<(&str, K)>) -> Tokenizer<K> {
+        let mut tokenizer = Tokenizer::new(priority);
+        let matchers = matchers.into_iter()
+            .map(|(pattern, type_id)| Rule::try_new(pattern, type_id).unwrap())
+            .collect();
+        tokenizer.add_matchers_to(INITIAL_MODE, matchers);
         tokenizer
     }
 
-    pub fn add_matcher(&mut self, matcher: Matcher) {
-        self.matchers.push(matcher);
+    // Adds a literal rule to the initial mode for each string, see `Rule::literal`.
+    pub fn add_literals(&mut self, literals: Vec<(&str, K)>) {
+        let rules = literals.into_iter().map(|(text, typ)| Rule::literal(text, typ)).collect();
+        self.add_matchers_to(INITIAL_MODE, rules);
+    }
+
+    pub fn add_matcher(&mut self, matcher: Rule<K>) {
+        self.add_matcher_to(INITIAL_MODE, matcher);
+    }
+
+    // Adds a matcher that is only tried while `mode` is the current mode, creating
+    // the mode if it doesn't exist yet.
+    pub fn add_matcher_to(&mut self, mode: &str, matcher: Rule<K>) {
+        self.add_matchers_to(mode, vec![matcher]);
+    }
+
+    pub fn add_matchers_to(&mut self, mode: &str, matchers: Vec<Rule<K>>) {
+        self.mode_mut(mode).add(matchers);
     }
 
-    pub fn tokenize<'a>(&self, input: &'a String) -> Vec<Token<'a>> {
-        let mut current = input.as_str();
+    pub(crate) fn mode_mut(&mut self, name: &str) -> &mut Mode<K> {
+        let idx = match self.mode_index(name) {
+            Some(idx) => idx,
+            None => {
+                self.modes.push(Mode::new(name));
+                self.modes.len() - 1
+            }
+        };
+        &mut self.modes[idx]
+    }
 
-        let mut result = Vec::new();
-        while current.len() != 0 {
+    pub(crate) fn mode_index(&self, name: &str) -> Option<usize> {
+        self.modes.iter().position(|m| m.name == name)
+    }
 
-            let mut currmatch : (TokenTypeId, Option<Captures>) = (0, None);
+    pub fn nullable_matchers(&self) -> Vec<(K, String)> {
+        self.modes.iter()
+            .flat_map(|mode| mode.matchers.iter())
+            .filter(|m| m.matches_empty())
+            .map(|m| (m.to_type, m.source.clone()))
+            .collect()
+    }
 
-            for matcher in &self.matchers {
+    pub fn tokenize<'a>(&self, input: &'a str) -> Result<Vec<Token<'a, K>>, TokenizeError<K>> {
+        self.tokens(input).collect()
+    }
 
-                fn match_length(m: &Captures) -> usize {
-                    m.get(0).unwrap().as_str().len()
-                }
+    // Like `tokenize`, for tokens that have to outlive `input`.
+    pub fn tokenize_owned(&self, input: &str) -> Result<Vec<OwnedToken<K>>, TokenizeError<K>> {
+        self.tokens(input).map(|t| t.map(Token::into_owned)).collect()
+    }
 
-                let match_ = matcher.pattern.captures(current);
+    // Like `tokenize`, but keeps going past input no matcher accepts, for editors
+    // and linters. Each run of such input becomes one token of `error_type`, and
+    // lexing picks up again at the first position where a match succeeds. Returns
+    // the tokens along with an error for each run, whose span and text cover the
+    // whole run.
+    pub fn tokenize_recovering<'a>(&self, input: &'a str, error_type: K) -> (Vec<Token<'a, K>>, Vec<TokenizeError<K>>) {
+        let mut cursor = Cursor::start();
+        let mut trivia = TriviaAttacher::new();
+        let mut tokens = Vec::new();
+        let mut errors = Vec::new();
+        // where the current run of bad input starts and the error at its start
+        let mut bad: Option<(Location, TokenizeError<K>)> = None;
+        let mut pos = 0;
 
-                if ! match_.is_some() {
+        // the error token and diagnostic for a run from `start` to `end`
+        let recovered = |start: Location, mut error: TokenizeError<K>, end: usize| {
+            let text = &input[start.offset..end];
+            let span = start.span_to(end);
+            error.span = span;
+            error.text = text.to_string();
+            let token = Token {
+                typ: error_type,
+                parts: vec![Some(text)],
+                span,
+                part_spans: vec![Some(span)],
+                names: Arc::new(vec![None]),
+                leading_trivia: Vec::new(),
+                trailing_trivia: Vec::new(),
+            };
+            (token, error)
+        };
+
+        while pos < input.len() {
+            let current = &input[pos..];
+            let (emitted, consumed, channel) = match self.step(&mut cursor, current, true) {
+                Step::Token(token, consumed, channel) => (vec![token], consumed, channel),
+                Step::Tokens(emitted, consumed, channel) => (emitted, consumed, channel),
+                Step::Failed(e) => {
+                    let location = cursor.location;
+                    bad.get_or_insert((location, e));
+                    let len = current.chars().next().unwrap().len_utf8();
+                    cursor.location = location.advance(&current[..len]);
+                    pos += len;
                     continue;
-                }
+                },
+                Step::NeedMore => unreachable!("complete input never needs more"),
+            };
 
-                let hasmatch = currmatch.1.is_some();
+            if let Some((start, error)) = bad.take() {
+                let (token, error) = recovered(start, error, pos);
+                tokens.extend(trivia.push(token, Channel::Main));
+                errors.push(error);
+            }
+            for token in emitted {
+                tokens.extend(trivia.push(token, channel));
+            }
+            pos += consumed;
+        }
+        if let Some((start, error)) = bad.take() {
+            let (token, error) = recovered(start, error, pos);
+            tokens.extend(trivia.push(token, Channel::Main));
+            errors.push(error);
+        }
+        tokens.extend(trivia.finish());
+        (tokens, errors)
+    }
 
-                if ! hasmatch {
-                    currmatch = (matcher.to_type, match_);
-                    if self.priority == MatcherPriority::First {
-                        break;
-                    } else {
-                        continue;
-                    }
+    pub fn tokens<'t, 'a>(&'t self, input: &'a str) -> Tokens<'t, 'a, K> {
+        Tokens {
+            tokenizer: self,
+            current: input,
+            cursor: Cursor::start(),
+            trivia: TriviaAttacher::new(),
+            ready: VecDeque::new(),
+            error: None,
+            done: false,
+        }
+    }
+
+    // Lexes one token from the start of `current`, which is at `cursor`. Unless
+    // `complete` says `current` runs to the end of the input, asks for more input
+    // whenever that could change which token is picked.
+    pub(crate) fn step<'a>(&self, cursor: &mut Cursor<K>, current: &'a str, complete: bool) -> Step<'a, K> {
+        let mode = &self.modes[*cursor.modes.last().unwrap()];
+        let location = cursor.location;
+
+        let previous = cursor.previous;
+        let (found, furthest) = match self.find_match(mode, current, |matcher| matcher.allows(previous)) {
+            Ok(found) => found,
+            Err(kind) => return Step::Failed(TokenizeError::new(kind, &mode.matchers, location, current)),
+        };
+        // with nothing found, only a custom matcher might still match once it sees
+        // more, for all we know
+        let enabled = |matcher: &Rule<K>| matcher.allows(previous);
+        if !complete && (furthest == current.len() || mode.may_extend(current, enabled)
+            || (found.is_none() && mode.matchers.iter().any(|m| m.is_custom() && enabled(m)))) {
+            return Step::NeedMore;
+        }
+
+        let (idx, end) = match found {
+            Some(found) => found,
+            None => return Step::Failed(TokenizeError::no_match(&mode.matchers, location, current)),
+        };
+        let matcher = &mode.matchers[idx];
+
+        if end == 0 {
+            return Step::Failed(TokenizeError::empty_match(&mode.matchers, location, current, matcher.to_type));
+        }
+
+        if let Err(kind) = self.change_mode(cursor, &matcher.mode_change) {
+            return Step::Failed(TokenizeError::new(kind, &mode.matchers, location, current));
+        }
+
+        let groups = matcher.groups(current);
+        let (mut token, after) = Token::from_match(matcher.to_type, &matcher.names, &groups, location, current);
+        if let Some(typ) = mode.keywords.iter().find_map(|k| k.retag(token.typ, &current[..end], cursor.previous)) {
+            token.typ = typ;
+        }
+        cursor.location = after;
+
+        if let Some(ref action) = matcher.action {
+            let tokens = action(token, &mut cursor.state);
+            if matcher.channel == Channel::Main {
+                if let Some(last) = tokens.last() {
+                    cursor.previous = Some(last.typ);
                 }
+            }
+            return Step::Tokens(tokens, end, matcher.channel);
+        }
+        if matcher.channel == Channel::Main {
+            cursor.previous = Some(token.typ);
+        }
+        Step::Token(token, end, matcher.channel)
+    }
 
-                let currentlen = match_length(currmatch.1.as_ref().unwrap());
-                let nextlen = match_length(match_.as_ref().unwrap());
+    fn change_mode(&self, cursor: &mut Cursor<K>, change: &ModeChange) -> Result<(), TokenizeErrorKind<K>> {
+        let find = |name: &String| self.mode_index(name).ok_or_else(|| TokenizeErrorKind::UnknownMode(name.clone()));
 
-                match self.priority {
-                    MatcherPriority::Longest => {
-                        if currentlen < nextlen {
-                            currmatch = (matcher.to_type, match_);
-                        }
-                    },
-                    MatcherPriority::First => {},
-                    MatcherPriority::Shortest => {
-                        if currentlen > nextlen {
-                            currmatch = (matcher.to_type, match_);
-                        }
-                    }
+        match change {
+            ModeChange::Stay => {},
+            ModeChange::Push(name) => cursor.modes.push(find(name)?),
+            ModeChange::Pop => {
+                if cursor.modes.len() == 1 {
+                    return Err(TokenizeErrorKind::PopLastMode);
                 }
+                cursor.modes.pop();
+            },
+            ModeChange::Switch(name) => *cursor.modes.last_mut().unwrap() = find(name)?,
+        }
+        Ok(())
+    }
+
+    // Picks the winning matcher of `mode` at the start of `current` among those
+    // `enabled` accepts, and the end of its match. Also returns the furthest end
+    // of any match that was looked at, so callers with partial input can tell
+    // whether more input could change the result.
+    //
+    // All regex patterns are run at once through the mode's set; only the
+    // candidates it reports are looked at again.
+    pub(crate) fn find_match<F>(&self, mode: &Mode<K>, current: &str, enabled: F) -> Result<Picked, TokenizeErrorKind<K>>
+    where F: Fn(&Rule<K>) -> bool {
+        let mut bad = None;
+        let found = {
+            let matches = mode.candidates(current, &enabled).into_iter()
+                .map(|idx| (idx, &mode.matchers[idx]))
+                .filter(|&(_, matcher)| enabled(matcher))
+                .filter_map(|(idx, matcher)| match matcher.find(current) {
+                    Ok(end) => end.map(|end| (idx, end, matcher.priority)),
+                    Err(end) => {
+                        bad.get_or_insert(TokenizeErrorKind::BadMatchEnd(matcher.to_type, end));
+                        None
+                    },
+                });
+            self.priority.pick(matches)
+        };
+        match bad {
+            Some(kind) => Err(kind),
+            None => Ok(found),
+        }
+    }
+}
+
+// Lazily lexes `current`, producing one token per call to `next`. Stops after the
+// first error.
+pub struct Tokens<'t, 'a, K: 't = TokenTypeId> {
+    tokenizer: &'t Tokenizer<K>,
+    current: &'a str,
+    cursor: Cursor<K>,
+    trivia: TriviaAttacher<Token<'a, K>>,
+    // tokens the trivia attacher let go of that haven't been handed out yet
+    ready: VecDeque<Token<'a, K>>,
+    error: Option<TokenizeError<K>>,
+    done: bool,
+}
+
+impl <'t, 'a, K: Copy + PartialEq> Iterator for Tokens<'t, 'a, K> {
+    type Item = Result<Token<'a, K>, TokenizeError<K>>;
+
+    fn next(&mut self) -> Option<Result<Token<'a, K>, TokenizeError<K>>> {
+        loop {
+            if let Some(token) = self.ready.pop_front() {
+                return Some(Ok(token));
+            }
+            if let Some(e) = self.error.take() {
+                return Some(Err(e));
+            }
+            if self.done {
+                return None;
+            }
+            if self.current.is_empty() {
+                self.done = true;
+                return self.trivia.finish().map(Ok);
             }
 
-            if let (mtype, Some(m)) = currmatch {
-                current = &current[m.get(0).unwrap().end()..];
-                result.push( Token { typ: mtype , parts: m.iter().map(|i| i.map(|s| s.as_str())).collect() });
-            } else {
-                break;
+            match self.tokenizer.step(&mut self.cursor, self.current, true) {
+                Step::Token(token, consumed, channel) => {
+                    self.current = &self.current[consumed..];
+                    if let Some(token) = self.trivia.push(token, channel) {
+                        return Some(Ok(token));
+                    }
+                },
+                Step::Tokens(tokens, consumed, channel) => {
+                    self.current = &self.current[consumed..];
+                    for token in tokens {
+                        self.ready.extend(self.trivia.push(token, channel));
+                    }
+                },
+                Step::Failed(e) => {
+                    // hand out the token held back for trivia before the error
+                    self.done = true;
+                    self.error = Some(e);
+                    if let Some(token) = self.trivia.finish() {
+                        return Some(Ok(token));
+                    }
+                },
+                Step::NeedMore => unreachable!("complete input never needs more"),
             }
         }
-
-        return result;
     }
 }